@@ -1,70 +1,207 @@
 
 // Local imports
-use crate::blockchain::transaction::{Transaction, NULL_TRANSACTION};
+use crate::blockchain::{Target, Transaction};
+use crate::blockchain::encoding::{Decode, DecodeError, Encode, Reader};
+use crate::blockchain::merkle::{merkle_root, MerkleProof};
 // Std imports
 use std::sync::Arc;
 // External imports
-use lazy_static::*;
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 
-const HASH_SIZE: usize = 32;
+pub const HASH_SIZE: usize = 32;
 const NULL_HASH: &'static [u8; HASH_SIZE] = b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+// Size of the serialized header, everything but the nonce then the nonce
+pub const HEADER_PREFIX_SIZE: usize = 8 + HASH_SIZE + HASH_SIZE + 8 + 4;
+pub const HEADER_SIZE: usize = HEADER_PREFIX_SIZE + 8;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Block {
     index: u64,
-    // timestamp: DateTime<Utc>,
+    timestamp: DateTime<Utc>,
     hash: Arc<[u8; HASH_SIZE]>,
     previous_hash: Arc<[u8; HASH_SIZE]>,
-    transaction: Transaction,
+    // Summary of every transaction in the block, so the header alone commits to all of them
+    merkle_root: Arc<[u8; HASH_SIZE]>,
+    transactions: Vec<Transaction>,
+    // Compact encoding of the target this block's hash has to meet
+    bits: u32,
     nonce: u64,
 }
 
+// Everything in a block but the transactions (which the merkle root commits to), enough to check the proof of work
+// Peers catching up download these first to find out which blocks they're missing
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: DateTime<Utc>,
+    pub hash: Arc<[u8; HASH_SIZE]>,
+    pub previous_hash: Arc<[u8; HASH_SIZE]>,
+    pub merkle_root: Arc<[u8; HASH_SIZE]>,
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    // The header fields that stay the same while mining, in the order they're hashed
+    pub fn header_prefix(&self) -> [u8; HEADER_PREFIX_SIZE] {
+        let mut bytes = [0u8; HEADER_PREFIX_SIZE];
+        // Timestamp (only supports 584 years and will break around the year 2600)
+        bytes[0..8].copy_from_slice(&self.timestamp.timestamp_nanos().to_le_bytes());
+        // The transactions (through the merkle root)
+        bytes[8..40].copy_from_slice(self.merkle_root.as_ref());
+        bytes[40..72].copy_from_slice(self.previous_hash.as_ref());
+        bytes[72..80].copy_from_slice(&self.index.to_le_bytes());
+        // The target so it can't be swapped for an easier one
+        bytes[80..84].copy_from_slice(&self.bits.to_le_bytes());
+
+        bytes
+    }
+    // Fixed layout header that the hash is computed over, the nonce goes last so the rest can be hashed once
+    pub fn header_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..HEADER_PREFIX_SIZE].copy_from_slice(&self.header_prefix());
+        bytes[HEADER_PREFIX_SIZE..].copy_from_slice(&self.nonce.to_le_bytes());
+
+        bytes
+    }
+    // Read a header back from its fixed layout, the hash is recomputed rather than trusted
+    fn from_header_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let timestamp = Utc.timestamp_nanos(reader.u64()? as i64);
+        let merkle_root = reader.hash()?;
+        let previous_hash = reader.hash()?;
+        let index = reader.u64()?;
+        let bits = reader.u32()?;
+        let nonce = reader.u64()?;
+
+        Ok(Self {
+            index,
+            timestamp,
+            hash: Arc::new(Sha256::digest(bytes).into()),
+            previous_hash: Arc::new(previous_hash),
+            merkle_root: Arc::new(merkle_root),
+            bits,
+            nonce,
+        })
+    }
+    // Check that the stored hash matches the header
+    pub fn has_valid_hash(&self) -> bool {
+        *self.hash == <[u8; HASH_SIZE]>::from(Sha256::digest(&self.header_bytes()))
+    }
+    // Whether the hash meets the target in the header (doesn't check the target is the right one)
+    pub fn meets_target(&self) -> bool {
+        Target::from_compact(self.bits).map_or(false, |target| target.is_met_by(&self.hash))
+    }
+}
+
 impl Block {
+    // Just the header, without the transactions
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            hash: self.hash.clone(),
+            previous_hash: self.previous_hash.clone(),
+            merkle_root: self.merkle_root.clone(),
+            bits: self.bits,
+            nonce: self.nonce,
+        }
+    }
+    // The header fields that stay the same while mining, in the order they're hashed
+    pub fn header_prefix(&self) -> [u8; HEADER_PREFIX_SIZE] {
+        self.header().header_prefix()
+    }
+    // Fixed layout header that the hash is computed over
+    pub fn header_bytes(&self) -> [u8; HEADER_SIZE] {
+        self.header().header_bytes()
+    }
+    // Hasher that has already taken in everything but the nonce, clone it to try each nonce
+    pub fn midstate(&self) -> Sha256 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.header_prefix());
+
+        hasher
+    }
+    // Finish the hash for a nonce from a midstate
+    pub fn hash_with_nonce(midstate: &Sha256, nonce: u64) -> [u8; HASH_SIZE] {
+        let mut hasher = midstate.clone();
+        hasher.update(nonce.to_le_bytes());
+
+        hasher.finalize().into()
+    }
+    // Compute what the hash should be from the other members
+    pub fn calculate_hash(&self) -> [u8; HASH_SIZE] {
+        Sha256::digest(&self.header_bytes()).into()
+    }
     // Update hash member from other members
     pub fn update_hash(&mut self) {
-        let mut hasher = Sha256::new();
-        // Hash the timestamp (only supports 584 years and will break around the year 2600)
-        // hasher.update(self.timestamp().timestamp_nanos().to_le_bytes());
-        // Hash the transaction
-        hasher.update(self.transaction().sender().as_bytes());
-        hasher.update(self.transaction().receiver().as_bytes());
-        hasher.update(self.transaction().amount().to_le_bytes());
-        // Include previous hash in hash
-        hasher.update(self.previous_hash().as_ref());
-        // Include index
-        hasher.update(self.index().to_le_bytes());
-        // Include nonce
-        hasher.update(self.nonce().to_le_bytes());
-
-        let hash = hasher.finalize();
-        let mut byte_array = [0u8; HASH_SIZE];
-        for i in 0..HASH_SIZE {
-            let t = hash.as_slice()[i];
-            byte_array[i] = t;
-        }
-        self.hash = Arc::new(byte_array);
+        self.hash = Arc::new(self.calculate_hash());
+    }
+    // Check that the stored hash matches the block's contents
+    pub fn has_valid_hash(&self) -> bool {
+        *self.hash == self.calculate_hash()
+    }
+    // Compute the merkle root from the transactions
+    pub fn calculate_merkle_root(&self) -> [u8; HASH_SIZE] {
+        merkle_root(&self.transaction_hashes())
+    }
+    // Check that the stored merkle root matches the block's transactions
+    pub fn has_valid_merkle_root(&self) -> bool {
+        *self.merkle_root == self.calculate_merkle_root()
+    }
+    // Proof that the transaction at index is in this block, which can be checked against just the merkle root
+    pub fn merkle_proof(&self, index: usize) -> Option<MerkleProof> {
+        MerkleProof::generate(&self.transaction_hashes(), index)
+    }
+    fn transaction_hashes(&self) -> Vec<[u8; HASH_SIZE]> {
+        self.transactions.iter().map(Transaction::hash).collect()
     }
     // Constructor
-    pub fn new(transaction: Transaction, index: u64, previous_hash: Arc<[u8; HASH_SIZE]>) -> Self {
-        // let timestamp = Utc::now();
+    pub fn new(transactions: Vec<Transaction>, index: u64, previous_hash: Arc<[u8; HASH_SIZE]>, bits: u32) -> Self {
+        let timestamp = Utc::now();
         let mut block = Self {
             hash: Arc::new(*NULL_HASH),
             nonce: 0,
             index,
             previous_hash,
-            // timestamp,
-            transaction,
+            timestamp,
+            merkle_root: Arc::new(*NULL_HASH),
+            transactions,
+            bits,
         };
+        block.merkle_root = Arc::new(block.calculate_merkle_root());
         // Set the block's hash from its properties
         block.update_hash();
 
         block
     }
+    // The first block of a chain, it isn't mined so it has no target or nonce (see Genesis::block)
+    pub fn genesis(timestamp: DateTime<Utc>, previous_hash: [u8; HASH_SIZE], transactions: Vec<Transaction>) -> Self {
+        let mut block = Self {
+            hash: Arc::new(*NULL_HASH),
+            nonce: 0,
+            index: 0,
+            previous_hash: Arc::new(previous_hash),
+            timestamp,
+            merkle_root: Arc::new(*NULL_HASH),
+            transactions,
+            bits: 0,
+        };
+        block.merkle_root = Arc::new(block.calculate_merkle_root());
+        block.update_hash();
+
+        block
+    }
     // Setters
-    pub fn increment_nonce(&mut self) {
-        self.nonce += 1;
+    pub fn set_nonce(&mut self, nonce: u64) {
+        self.nonce = nonce;
+        self.update_hash();
+    }
+    #[cfg(test)]
+    pub fn set_timestamp(&mut self, timestamp: DateTime<Utc>) {
+        self.timestamp = timestamp;
         self.update_hash();
     }
     // Getters
@@ -74,12 +211,30 @@ impl Block {
     pub fn previous_hash(&self) -> Arc<[u8; HASH_SIZE]> {
         self.previous_hash.clone()
     }
-    pub fn transaction(&self) -> Transaction {
-        self.transaction.clone()
+    pub fn merkle_root(&self) -> Arc<[u8; HASH_SIZE]> {
+        self.merkle_root.clone()
+    }
+    pub fn transactions(&self) -> &[Transaction] {
+        self.transactions.as_slice()
+    }
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+    // The target this block claims to meet, None if the bits aren't a valid encoding
+    pub fn target(&self) -> Option<Target> {
+        Target::from_compact(self.bits)
+    }
+    // Expected number of hashes it took to mine this block, what chains are weighed by
+    pub fn work(&self) -> u128 {
+        self.target().map_or(0, |target| target.work())
+    }
+    // Whether the hash meets the target in the header (doesn't check the target is the right one)
+    pub fn meets_target(&self) -> bool {
+        self.target().map_or(false, |target| target.is_met_by(&self.hash))
     }
-    // pub fn timestamp(&self) -> DateTime<Utc> {
-    //     self.timestamp
-    // }
     pub fn nonce(&self) -> u64 {
         self.nonce
     }
@@ -88,19 +243,40 @@ impl Block {
     }
 }
 
-lazy_static! {
-    pub static ref GENESIS_BLOCK: Block = {
-        let mut block = Block {
-            hash: Arc::new(*NULL_HASH),
-            nonce: 0, // NOTE: We might want to find a nonce that actually makes the first few digits zero (but it isn't important since this is just the root)
-            index: 0,
-            previous_hash: Arc::new(*NULL_HASH),
-            transaction: NULL_TRANSACTION,
-            // timestamp: DateTime::parse_from_rfc3339("2020-10-11T08:49:15Z").unwrap().with_timezone(&Utc),
-        };
-        // Set the block's hash from its properties
-        block.update_hash();
+// Headers are encoded as the same fixed layout they're hashed from
+impl Encode for BlockHeader {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.header_bytes());
+    }
+}
 
-        block
-    };
+impl Decode for BlockHeader {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Self::from_header_bytes(reader.take(HEADER_SIZE)?)
+    }
+}
+
+// A block is its header followed by its transactions
+impl Encode for Block {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        self.header().encode_to(bytes);
+        self.transactions.encode_to(bytes);
+    }
+}
+
+impl Decode for Block {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        let header = BlockHeader::decode_from(reader)?;
+
+        Ok(Self {
+            index: header.index,
+            timestamp: header.timestamp,
+            hash: header.hash,
+            previous_hash: header.previous_hash,
+            merkle_root: header.merkle_root,
+            transactions: Vec::decode_from(reader)?,
+            bits: header.bits,
+            nonce: header.nonce,
+        })
+    }
 }
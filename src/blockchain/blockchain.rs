@@ -1,115 +1,1081 @@
 
 // Local imports
-use crate::blockchain::{Block, GENESIS_BLOCK, Transaction};
+use crate::blockchain::{Block, BlockchainError, BlockHeader, Genesis, HASH_SIZE, Target, Transaction};
+use crate::blockchain::storage::{Account, BlockEntry, BlockHash, MemoryStorage, StateUpdate, Storage};
+// Std imports
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
 // External imports
-use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Duration, Utc};
 use crate::blockchain::transaction::CurrencyType;
 use std::collections::HashMap;
 use libp2p::PeerId;
 
-pub const STARTING_BALANCE: CurrencyType = 5000;
+// Most transactions a single block can hold
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+// How many of the previous blocks a new block's timestamp has to beat the median of
+const MEDIAN_TIME_SPAN: usize = 11;
+// How far ahead of our clock a block's timestamp can be by default (2 hours)
+const DEFAULT_MAX_FUTURE_DRIFT_SECONDS: i64 = 2 * 60 * 60;
+// Most the target can change by in one retarget (either way), so a few bad timestamps can't swing it wildly
+const MAX_RETARGET_FACTOR: i64 = 4;
+// Branches can only split off from the last this many blocks of the main chain, anything older is settled
+// (otherwise anyone could fill our storage with cheap forks off the easy early blocks)
+const MAX_FORK_DEPTH: u64 = 100;
+// How many of the newest blocks go in a locator one after another before the gaps start doubling
+const LOCATOR_DENSE_BLOCKS: usize = 10;
 
-#[derive(Debug, Deserialize, Serialize)]
+// The first invalid block found when validating a chain
+#[derive(Debug, Clone)]
+pub struct InvalidBlock {
+    // Position of the block in the chain
+    pub position: usize,
+    pub hash: Arc<[u8; HASH_SIZE]>,
+    pub error: BlockchainError,
+}
+
+// How the main chain changed after adding a block (empty if the block went on a side branch)
+#[derive(Debug, Clone, Default)]
+pub struct ChainUpdate {
+    // Blocks that are no longer in the main chain, newest first
+    pub disconnected: Vec<Block>,
+    // Blocks that were added to the main chain, oldest first
+    pub connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    pub fn is_reorganization(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+}
+
+// Account changes made while checking blocks on top of what's in storage (only written back once everything checks out)
+struct AccountChanges<'a> {
+    storage: &'a dyn Storage,
+    accounts: HashMap<String, Account>,
+}
+
+impl<'a> AccountChanges<'a> {
+    fn new(storage: &'a dyn Storage) -> Self {
+        Self { storage, accounts: HashMap::new() }
+    }
+
+    fn get(&self, peer_id: &str) -> Account {
+        self.accounts.get(peer_id).copied().unwrap_or_else(|| self.storage.account(peer_id))
+    }
+
+    fn set(&mut self, peer_id: &str, account: Account) {
+        self.accounts.insert(peer_id.to_string(), account);
+    }
+}
+
+#[derive(Debug)]
 pub struct Blockchain {
-    balances: HashMap<String, CurrencyType>,
-    block_chain: Vec<Block>,
-    difficulty: u8,
+    // Allocations the chain started from (an account that isn't in here and hasn't been paid by a block has nothing)
+    genesis: Genesis,
+    // Every valid block we know about (including ones on competing branches), the main chain and the accounts as of its tip
+    storage: Box<dyn Storage>,
+    // Last block of the main chain (kept out here since it's needed all the time)
+    tip: BlockEntry,
+    // Target the first blocks after genesis have to meet
+    initial_target: Target,
+    // How often we aim to mine a block, the target is adjusted to match (from the genesis, like the retarget interval and the subsidy)
+    target_block_seconds: i64,
+    // How many blocks go by between target adjustments
+    retarget_interval: u64,
+    // How far ahead of our clock a block's timestamp can be
+    max_future_drift_seconds: i64,
+    // New money paid to the miner of the first block
+    initial_subsidy: CurrencyType,
+    // How many blocks go by before the subsidy halves (None to keep it the same forever)
+    halving_interval: Option<u64>,
 }
 
 impl Blockchain {
-    fn block_meets_difficulty(&self, block: &Block) -> bool {
-        let hash = *block.hash();
-        for i in 0..self.difficulty {
-            if hash[i as usize] != 0 {
-                return false;
-            }
+    // Target a block built on top of parent has to meet (every node works this out the same way from the timestamps)
+    pub fn next_target(&self, parent: &BlockHash) -> Target {
+        let parent_entry = self.entry(parent);
+        let height = parent_entry.block.index() + 1;
+        // The interval is measured from the block before it, so the first one would start at the genesis block,
+        // whose fixed timestamp says nothing about how fast blocks are mined
+        if height % self.retarget_interval != 0 || height <= self.retarget_interval + 1 {
+            return parent_entry.target;
+        }
+
+        // Compare how long the last interval took against how long it should have taken
+        // (from the block before the interval to the last block in it, so it covers retarget_interval blocks' worth of time)
+        let mut first_block = parent_entry.block.clone();
+        for _ in 0..self.retarget_interval {
+            first_block = self.entry(&first_block.previous_hash()).block;
         }
+        self.retarget(parent_entry.target, first_block.timestamp(), parent_entry.block.timestamp())
+    }
 
-        true
+    // Scale the target by how far off the actual time between two blocks was from what we aimed for
+    fn retarget(&self, target: Target, first_timestamp: DateTime<Utc>, last_timestamp: DateTime<Utc>) -> Target {
+        let expected_seconds = self.target_block_seconds * self.retarget_interval as i64;
+        let actual_seconds = (last_timestamp - first_timestamp).num_seconds()
+            .max(expected_seconds / MAX_RETARGET_FACTOR)
+            .min(expected_seconds * MAX_RETARGET_FACTOR)
+            .max(1);
+
+        // Round to what fits in a header so every node agrees on the exact target
+        target.scale(actual_seconds as u64, expected_seconds.max(1) as u64).rounded()
+    }
+
+    // Target the next block on the main chain has to meet
+    pub fn current_target(&self) -> Target {
+        self.next_target(&self.latest_block().hash())
+    }
+    pub fn is_valid_next_block(&self, block: &Block) -> Result<(), BlockchainError> {
+        self.check_next_block(block).map(|_| ())
     }
-    pub fn is_valid_next_block(&self, block: &Block) -> bool {
+
+    // Check a block against the latest block, returning the accounts that change when its transactions are applied
+    fn check_next_block(&self, block: &Block) -> Result<HashMap<String, Account>, BlockchainError> {
         let current_block = self.latest_block();
 
-        // TODO: Check that the transaction is valid, sender exists and has funds and receiver exists
+        if block.previous_hash() != current_block.hash() {
+            return Err(BlockchainError::BadPreviousHash);
+        }
+        self.check_timestamp(&self.ancestor_timestamps(&current_block.hash()), block)?;
+        if block.index() != current_block.index() + 1 {
+            return Err(BlockchainError::WrongIndex { expected: current_block.index() + 1, found: block.index() });
+        }
+
+        Self::check_transactions_header(block)?;
+
+        // Check that every transaction is valid, the senders have the funds for them and the miner paid themself the right amount
+        let mut changes = AccountChanges::new(self.storage.as_ref());
+        self.apply_block(&mut changes, block)?;
+
+        Ok(changes.accounts)
+    }
+
+    // Apply every transaction in a block, then pay the miner the subsidy plus the fees through the coinbase
+    fn apply_block(&self, changes: &mut AccountChanges, block: &Block) -> Result<(), BlockchainError> {
+        let (coinbase, transactions) = block.transactions().split_first().ok_or(BlockchainError::MissingCoinbase)?;
+        if !coinbase.is_coinbase() {
+            return Err(BlockchainError::MissingCoinbase);
+        }
+        if coinbase.fee() != 0 || !coinbase.signature().is_empty() || coinbase.nonce() != block.index() {
+            return Err(BlockchainError::InvalidCoinbase);
+        }
+        if PeerId::from_str(&coinbase.receiver).is_err() {
+            return Err(BlockchainError::InvalidPeerId(coinbase.receiver()));
+        }
+
+        let mut fees: CurrencyType = 0;
+        for transaction in transactions {
+            Self::apply_transaction(changes, transaction)?;
+            fees = fees.saturating_add(transaction.fee());
+        }
+        let expected_reward = self.block_subsidy(block.index()).saturating_add(fees);
+        if coinbase.amount() != expected_reward {
+            return Err(BlockchainError::WrongReward { expected: expected_reward, found: coinbase.amount() });
+        }
+        let mut miner = changes.get(&coinbase.receiver);
+        miner.balance = miner.balance.checked_add(coinbase.amount()).ok_or(BlockchainError::MalformedBlock)?;
+        changes.set(&coinbase.receiver, miner);
+
+        Ok(())
+    }
+
+    // Undo a block that was applied with apply_block (blocks have to be undone newest first)
+    fn revert_block(changes: &mut AccountChanges, block: &Block) {
+        if let Some((coinbase, transactions)) = block.transactions().split_first() {
+            let mut miner = changes.get(&coinbase.receiver);
+            miner.balance -= coinbase.amount();
+            changes.set(&coinbase.receiver, miner);
+            for transaction in transactions.iter().rev() {
+                Self::revert_transaction(changes, transaction);
+            }
+        }
+    }
+
+    // New money paid to the miner of the block at index (on top of the fees)
+    pub fn block_subsidy(&self, index: u64) -> CurrencyType {
+        let halvings = self.halving_interval.map_or(0, |interval| index / interval);
+
+        // Halved away to nothing once it's shifted past the last bit
+        u32::try_from(halvings).ok()
+            .and_then(|halvings| self.initial_subsidy.checked_shr(halvings))
+            .unwrap_or(0)
+    }
+
+    // Check that the transactions could go in the next block (in this order), without changing anything
+    pub fn check_transactions(&self, transactions: &[Transaction]) -> Result<(), (usize, BlockchainError)> {
+        let mut changes = AccountChanges::new(self.storage.as_ref());
+        for (position, transaction) in transactions.iter().enumerate() {
+            Self::apply_transaction(&mut changes, transaction).map_err(|error| (position, error))?;
+        }
+
+        Ok(())
+    }
+
+    // The block can't be too big and its merkle root has to match its transactions
+    fn check_transactions_header(block: &Block) -> Result<(), BlockchainError> {
+        if block.transactions().len() > MAX_BLOCK_TRANSACTIONS {
+            return Err(BlockchainError::TooManyTransactions { max: MAX_BLOCK_TRANSACTIONS, found: block.transactions().len() });
+        }
+        if !block.has_valid_merkle_root() {
+            return Err(BlockchainError::BadMerkleRoot);
+        }
+
+        Ok(())
+    }
+
+    // A block has to be newer than the median of the last few blocks before it, but can't be too far in the future
+    fn check_timestamp(&self, previous_timestamps: &[DateTime<Utc>], block: &Block) -> Result<(), BlockchainError> {
+        let median = Self::median_time_past(previous_timestamps);
+        if block.timestamp() <= median {
+            return Err(BlockchainError::TimestampTooOld { median, found: block.timestamp() });
+        }
+        let latest = Utc::now() + Duration::seconds(self.max_future_drift_seconds);
+        if block.timestamp() > latest {
+            return Err(BlockchainError::TimestampTooNew { latest, found: block.timestamp() });
+        }
+
+        Ok(())
+    }
+
+    // Median of the last MEDIAN_TIME_SPAN timestamps (using the median means one bad clock can't drag it around)
+    fn median_time_past(timestamps: &[DateTime<Utc>]) -> DateTime<Utc> {
+        let start = timestamps.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut timestamps = timestamps[start..].to_vec();
+        timestamps.sort();
+
+        timestamps[timestamps.len() / 2]
+    }
+
+    // Timestamps of the block and the blocks before it in its branch (up to MEDIAN_TIME_SPAN of them, oldest first)
+    fn ancestor_timestamps(&self, hash: &BlockHash) -> Vec<DateTime<Utc>> {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut cursor = self.storage.block(hash);
+        while let Some(entry) = cursor {
+            timestamps.push(entry.block.timestamp());
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            cursor = self.storage.block(&entry.block.previous_hash());
+        }
+        timestamps.reverse();
 
-        block.previous_hash() == current_block.hash()
-        // && block.timestamp() > current_block.timestamp()
-        && block.index() > current_block.index()
+        timestamps
+    }
+
+    // Re-verify every block from genesis and replay the balances, returning the first invalid block
+    pub fn validate(&self) -> Result<(), InvalidBlock> {
+        let genesis_hash = self.genesis.block().hash();
+        // Replay from the genesis allocations instead of what's in storage
+        let empty = MemoryStorage::new();
+        let mut changes = AccountChanges::new(&empty);
+        for (peer_id, balance) in self.genesis.balances() {
+            changes.set(&peer_id, Account { balance, nonce: 0 });
+        }
+        let mut target = self.initial_target;
+        // Timestamps of the main chain so far, for the median time and retargeting
+        let mut timestamps: Vec<DateTime<Utc>> = Vec::new();
+        let mut previous_block: Option<Block> = None;
+
+        for height in 0..self.storage.main_chain_length() {
+            let position = height as usize;
+            let block = self.storage.main_chain_hash(height).and_then(|hash| self.storage.block(&hash))
+                .ok_or(InvalidBlock { position, hash: Arc::new([0u8; HASH_SIZE]), error: BlockchainError::UnknownParent })?
+                .block;
+            let invalid = |error| InvalidBlock { position, hash: block.hash(), error };
+
+            if !block.has_valid_hash() {
+                return Err(invalid(BlockchainError::MalformedBlock));
+            }
+            match &previous_block {
+                // The genesis block isn't mined and its allocations are where the balances start
+                None => {
+                    if block.hash() != genesis_hash {
+                        return Err(invalid(BlockchainError::WrongGenesis));
+                    }
+                },
+                Some(previous_block) => {
+                    // Recompute the target from the timestamps instead of trusting the one we stored
+                    // (skipping the first interval, the same as next_target)
+                    if height % self.retarget_interval == 0 && height > self.retarget_interval + 1 {
+                        let first_timestamp = timestamps[position - 1 - self.retarget_interval as usize];
+                        target = self.retarget(target, first_timestamp, previous_block.timestamp());
+                    }
+                    if block.bits() != target.to_compact() {
+                        return Err(invalid(BlockchainError::WrongBits { expected: target.to_compact(), found: block.bits() }));
+                    }
+                    if !block.meets_target() {
+                        return Err(invalid(BlockchainError::InsufficientDifficulty));
+                    }
+                    if block.previous_hash() != previous_block.hash() {
+                        return Err(invalid(BlockchainError::BadPreviousHash));
+                    }
+                    self.check_timestamp(&timestamps, &block).map_err(invalid)?;
+                    if block.index() != previous_block.index() + 1 || block.index() != height {
+                        return Err(invalid(BlockchainError::WrongIndex { expected: height, found: block.index() }));
+                    }
+
+                    // Replay the transactions the same way add_block applies them
+                    Self::check_transactions_header(&block).map_err(invalid)?;
+                    self.apply_block(&mut changes, &block).map_err(invalid)?;
+                },
+            }
+
+            timestamps.push(block.timestamp());
+            previous_block = Some(block);
+        }
+
+        // Reverting blocks can leave accounts with nothing in them, which are the same as no account at all
+        let replayed: HashMap<String, Account> = changes.accounts.into_iter().filter(|(_, account)| *account != Account::default()).collect();
+        let stored: HashMap<String, Account> = self.storage.accounts().into_iter().filter(|(_, account)| *account != Account::default()).collect();
+        if replayed != stored || previous_block.map_or(true, |block| block.hash() != self.latest_block().hash()) {
+            let position = self.storage.main_chain_length().saturating_sub(1) as usize;
+            return Err(InvalidBlock { position, hash: self.latest_block().hash(), error: BlockchainError::BalanceMismatch });
+        }
+
+        Ok(())
     }
 
     pub fn latest_block(&self) -> &Block {
-        self.block_chain.last().unwrap()
+        &self.tip.block
     }
 
-    pub fn block_chain(&self) -> &[Block] {
-        self.block_chain.as_slice()
+    // Number of blocks in the main chain after genesis
+    pub fn height(&self) -> u64 {
+        self.tip.block.index()
     }
 
-    pub fn add_peer(&mut self, peer: String) {
-        // If we don't have a peer's balance
-        if !self.balances.contains_key(&peer) {
-            // println!("ADDING NEW PEER {}", peer);
-            // Insert the starting balance for that peer
-            self.balances.insert(peer, STARTING_BALANCE);
+    // The main chain block at a height
+    pub fn block_at_height(&self, height: u64) -> Option<Block> {
+        self.storage.main_chain_hash(height).and_then(|hash| self.get_block(&hash))
+    }
+
+    // Total work of the main chain, what peers compare to decide who is behind
+    pub fn total_work(&self) -> u128 {
+        self.tip.total_work
+    }
+
+    // Hashes of main chain blocks going back from the tip, one after another at first then doubling the gap, ending at genesis
+    // A peer finds where its chain split from ours with the first one it has, however far apart we are
+    pub fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = Vec::new();
+        let mut height = self.height();
+        let mut step = 1;
+        loop {
+            if let Some(hash) = self.storage.main_chain_hash(height) {
+                locator.push(hash);
+            }
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= LOCATOR_DENSE_BLOCKS {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
         }
+
+        locator
+    }
+
+    // Up to max headers of the main chain after the first locator hash that's in it (after genesis if none are)
+    pub fn headers_after(&self, locator: &[BlockHash], max: u64) -> Vec<BlockHeader> {
+        let start = locator.iter()
+            .find(|hash| self.is_in_main_chain(hash))
+            .map_or(0, |hash| self.entry(hash).block.index());
+
+        (start + 1..=self.height()).take(max as usize)
+            .filter_map(|height| self.block_at_height(height))
+            .map(|block| block.header())
+            .collect()
+    }
+
+    // Any block we know about, expected to be there
+    fn entry(&self, hash: &BlockHash) -> BlockEntry {
+        self.storage.block(hash).expect("Block missing from storage")
     }
 
-    // Called to add created blocks (from peers or initialized from add_transaction)
-    pub fn add_block(&mut self, mut block: Block) {
-        while !self.block_meets_difficulty(&block) {
-            block.increment_nonce();
+    // Check a transaction against the given accounts without changing anything
+    fn check_transaction(changes: &AccountChanges, transaction: &Transaction) -> Result<(), BlockchainError> {
+        let Transaction { sender, receiver, amount, fee, .. } = transaction;
+        // Coinbases are only valid at the start of a block, where apply_block handles them
+        if transaction.is_coinbase() {
+            return Err(BlockchainError::UnexpectedCoinbase);
+        }
+        // Both ends have to be real peers or the money is lost
+        for peer in [sender, receiver].iter() {
+            if PeerId::from_str(peer).is_err() {
+                return Err(BlockchainError::InvalidPeerId(peer.to_string()));
+            }
+        }
+        // Only the owner of the sender's keypair can spend their money
+        if transaction.signature().is_empty() {
+            return Err(BlockchainError::MissingSignature);
+        }
+        if !transaction.has_valid_signature() {
+            return Err(BlockchainError::InvalidSignature);
+        }
+        // Each transaction has to be the sender's next one, so a captured transaction can't be applied twice
+        let sender_account = changes.get(sender);
+        let expected_nonce = sender_account.nonce;
+        if transaction.nonce() != expected_nonce {
+            return Err(BlockchainError::InvalidNonce { expected: expected_nonce, found: transaction.nonce() });
+        }
+        if sender == receiver {
+            return Err(BlockchainError::SelfTransfer);
+        }
+        if *amount == 0 {
+            return Err(BlockchainError::ZeroAmount);
         }
-        block.update_hash();
-        // Make sure we don't skip an index
-        assert_eq!(block.index() as usize, self.block_chain.len());
-        self.block_chain.push(block.clone());
 
-        // Update balances
-        let Transaction { sender, receiver, amount } = block.transaction();
-        // HACK: We always add peer here just so they have an entry in the balances table
-        self.add_peer(sender.clone());
-        // println!("adding block");
-        // Update sender balance
-        if self.balances.contains_key(&sender) {
-            *self.balances.get_mut(&sender).unwrap() -= amount;
-        } else {
-            panic!("Sending money from a user that doesn't exist");
+        // The sender pays the fee on top of the amount
+        let sender_balance = sender_account.balance;
+        let cost = amount.checked_add(*fee).ok_or(BlockchainError::InsufficientFunds { balance: sender_balance, amount: CurrencyType::MAX })?;
+        if sender_balance < cost {
+            return Err(BlockchainError::InsufficientFunds { balance: sender_balance, amount: cost });
+        }
+
+        Ok(())
+    }
+
+    // Move the transaction amount between accounts (any peer we haven't seen yet starts with nothing)
+    fn apply_transaction(changes: &mut AccountChanges, transaction: &Transaction) -> Result<(), BlockchainError> {
+        Self::check_transaction(changes, transaction)?;
+
+        // Work out both accounts before changing either so a failed transaction changes nothing
+        let Transaction { sender, receiver, amount, fee, .. } = transaction;
+        let mut sender_account = changes.get(sender);
+        let mut receiver_account = changes.get(receiver);
+        receiver_account.balance = receiver_account.balance.checked_add(*amount).ok_or(BlockchainError::MalformedBlock)?;
+        sender_account.balance -= amount + fee;
+        sender_account.nonce += 1;
+
+        changes.set(sender, sender_account);
+        changes.set(receiver, receiver_account);
+
+        Ok(())
+    }
+
+    // Undo a transaction that was applied with apply_transaction (transactions have to be undone newest first)
+    fn revert_transaction(changes: &mut AccountChanges, transaction: &Transaction) {
+        let Transaction { sender, receiver, amount, fee, .. } = transaction;
+        let mut receiver_account = changes.get(receiver);
+        receiver_account.balance -= amount;
+        changes.set(receiver, receiver_account);
+        let mut sender_account = changes.get(sender);
+        sender_account.balance += amount + fee;
+        sender_account.nonce -= 1;
+        changes.set(sender, sender_account);
+    }
+
+    // Called to add mined blocks (from peers or ones our miner found)
+    // Blocks can extend any block we know about, the main chain switches to whichever branch has the most work
+    pub fn add_block(&mut self, block: Block) -> Result<ChainUpdate, BlockchainError> {
+        // Never trust the hash the sender published, and never do their proof of work for them
+        if !block.has_valid_hash() {
+            return Err(BlockchainError::MalformedBlock);
+        }
+        let hash = *block.hash();
+        if self.storage.contains_block(&hash) {
+            return Err(BlockchainError::AlreadyKnown);
+        }
+        let parent = self.storage.block(&block.previous_hash()).ok_or(BlockchainError::UnknownParent)?;
+        let fork_height = self.fork_height(&block.previous_hash());
+        if fork_height + MAX_FORK_DEPTH < self.height() {
+            return Err(BlockchainError::ForkTooDeep { fork_height, tip_height: self.height() });
+        }
+        let target = self.next_target(&block.previous_hash());
+        if block.bits() != target.to_compact() {
+            return Err(BlockchainError::WrongBits { expected: target.to_compact(), found: block.bits() });
+        }
+        if !block.meets_target() {
+            return Err(BlockchainError::InsufficientDifficulty);
+        }
+        let total_work = parent.total_work.saturating_add(block.work());
+
+        // Extending the main chain is the common case, check it against our current accounts
+        if block.previous_hash() == self.latest_block().hash() {
+            let accounts = self.check_next_block(&block)?;
+
+            let previous_height = self.height();
+            let entry = BlockEntry { block: block.clone(), target, total_work };
+            self.storage.insert_block(entry.clone());
+            self.storage.commit(StateUpdate { truncate_to: None, connected: vec![hash], accounts });
+            self.tip = entry;
+            self.prune_dead_branches(previous_height);
+
+            return Ok(ChainUpdate { disconnected: Vec::new(), connected: vec![block] });
         }
-        // HACK: We always add peer here just so they have an entry in the balances table
-        self.add_peer(receiver.clone());
-        // Update receiver balance
-        if self.balances.contains_key(&receiver) {
-            *self.balances.get_mut(&receiver).unwrap() += amount;
+
+        // Otherwise it's on a side branch, which only gets its transactions checked if it becomes the main chain
+        if block.index() != parent.block.index() + 1 {
+            return Err(BlockchainError::WrongIndex { expected: parent.block.index() + 1, found: block.index() });
+        }
+        self.check_timestamp(&self.ancestor_timestamps(&block.previous_hash()), &block)?;
+        Self::check_transactions_header(&block)?;
+        self.storage.insert_block(BlockEntry { block, target, total_work });
+
+        if total_work > self.tip.total_work {
+            let previous_height = self.height();
+            let update = self.reorganize(&hash)?;
+            self.prune_dead_branches(previous_height);
+
+            Ok(update)
         } else {
-            panic!("Sending money to a user that doesn't exist");
+            Ok(ChainUpdate::default())
+        }
+    }
+
+    // Switch the main chain over to the branch ending in new_tip, rolling back accounts to where the branches split
+    fn reorganize(&mut self, new_tip: &BlockHash) -> Result<ChainUpdate, BlockchainError> {
+        // Walk back from the new tip until we reach a block on the main chain
+        let mut connected = Vec::new();
+        let mut cursor = *new_tip;
+        while !self.is_in_main_chain(&cursor) {
+            let block = self.entry(&cursor).block;
+            cursor = *block.previous_hash();
+            connected.push(block);
+        }
+        connected.reverse();
+        let fork_index = self.entry(&cursor).block.index();
+        let disconnected: Vec<Block> = (fork_index + 1..self.storage.main_chain_length()).rev()
+            .filter_map(|height| self.block_at_height(height))
+            .collect();
+
+        let result = {
+            let mut changes = AccountChanges::new(self.storage.as_ref());
+            // Undo every main chain block after the fork (newest first)
+            for block in disconnected.iter() {
+                Self::revert_block(&mut changes, block);
+            }
+            // Replay the new branch, giving up on it (and keeping our current chain) if any of it is invalid
+            connected.iter()
+                .try_for_each(|block| self.apply_block(&mut changes, block).map_err(|error| (*block.hash(), error)))
+                .map(|_| changes.accounts)
+        };
+        let accounts = match result {
+            Ok(accounts) => accounts,
+            Err((invalid_hash, error)) => {
+                self.remove_branch(&invalid_hash);
+                return Err(error);
+            }
+        };
+
+        self.storage.commit(StateUpdate {
+            truncate_to: Some(fork_index + 1),
+            connected: connected.iter().map(|block| *block.hash()).collect(),
+            accounts,
+        });
+        self.tip = self.entry(new_tip);
+
+        Ok(ChainUpdate { disconnected, connected })
+    }
+
+    fn is_in_main_chain(&self, hash: &BlockHash) -> bool {
+        self.storage.block(hash)
+            .map_or(false, |entry| self.storage.main_chain_hash(entry.block.index()) == Some(*hash))
+    }
+
+    // Height of the last main chain block in the branch ending in hash (its own height if it's on the main chain)
+    fn fork_height(&self, hash: &BlockHash) -> u64 {
+        let mut cursor = self.entry(hash).block;
+        while !self.is_in_main_chain(&cursor.hash()) {
+            cursor = self.entry(&cursor.previous_hash()).block;
         }
+
+        cursor.index()
     }
 
-    pub fn new(difficulty: u8) -> Self {
-        Self {
-            balances: HashMap::new(),
-            block_chain: vec![GENESIS_BLOCK.clone()],
-            difficulty
+    // Forget side branches that split off too far below the tip to be added to anymore, now that the tip has moved up
+    // from previous_height (branches that were already too deep before that are gone already)
+    fn prune_dead_branches(&mut self, previous_height: u64) {
+        for fork_height in previous_height.saturating_sub(MAX_FORK_DEPTH)..self.height().saturating_sub(MAX_FORK_DEPTH) {
+            let main_child = self.storage.main_chain_hash(fork_height + 1);
+            let fork_hash = match self.storage.main_chain_hash(fork_height) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            for child in self.storage.children(&fork_hash) {
+                if Some(child) != main_child {
+                    self.remove_branch(&child);
+                }
+            }
         }
     }
 
-    // Attempt to add a transaction to the blockchain, if successful returns the block (called locally for self created transactions)
-    pub fn add_transaction(&mut self, transaction: Transaction) -> Option<&Block> {
-        let current_block = self.latest_block();
-        let block = Block::new(transaction, current_block.index() + 1, current_block.hash());
-        if self.is_valid_next_block(&block) {
-            self.add_block(block);
+    // Forget an invalid block and every block built on top of it
+    fn remove_branch(&mut self, hash: &BlockHash) {
+        let mut to_remove = vec![*hash];
+        while let Some(hash) = to_remove.pop() {
+            to_remove.extend(self.storage.children(&hash));
+            self.storage.remove_block(&hash);
+        }
+    }
 
-            self.block_chain.last()
-        } else {
-            None
+    // Look up any block we know about, in the main chain or not
+    pub fn get_block(&self, hash: &BlockHash) -> Option<Block> {
+        self.storage.block(hash).map(|entry| entry.block)
+    }
+
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        self.storage.contains_block(hash)
+    }
+
+    // A chain kept in memory
+    pub fn new(genesis: Genesis, initial_target: Target) -> Self {
+        Self::with_storage(genesis, initial_target, Box::new(MemoryStorage::new())).expect("Empty storage always takes the genesis block")
+    }
+
+    // A chain kept in the given storage, carrying on from whatever is already in there (which has to start from the same genesis)
+    pub fn with_storage(genesis: Genesis, initial_target: Target, mut storage: Box<dyn Storage>) -> Result<Self, BlockchainError> {
+        let initial_target = initial_target.rounded();
+        let genesis_block = genesis.block();
+        match storage.main_chain_hash(0) {
+            Some(hash) if hash != *genesis_block.hash() => return Err(BlockchainError::WrongGenesis),
+            Some(_) => {},
+            None => {
+                let accounts = genesis.balances().into_iter().map(|(peer_id, balance)| (peer_id, Account { balance, nonce: 0 })).collect();
+                storage.insert_block(BlockEntry { block: genesis_block.clone(), target: initial_target, total_work: 0 });
+                storage.commit(StateUpdate { truncate_to: None, connected: vec![*genesis_block.hash()], accounts });
+            },
         }
+        let tip_hash = storage.main_chain_hash(storage.main_chain_length() - 1).ok_or(BlockchainError::UnknownParent)?;
+        let tip = storage.block(&tip_hash).ok_or(BlockchainError::UnknownParent)?;
+        let target_block_seconds = (genesis.target_block_seconds as i64).max(1);
+        let retarget_interval = genesis.retarget_interval.max(1);
+        let initial_subsidy = genesis.block_subsidy;
+        let halving_interval = genesis.halving_interval.map(|interval| interval.max(1));
+
+        Ok(Self {
+            genesis,
+            storage,
+            tip,
+            initial_target,
+            target_block_seconds,
+            retarget_interval,
+            max_future_drift_seconds: DEFAULT_MAX_FUTURE_DRIFT_SECONDS,
+            initial_subsidy,
+            halving_interval,
+        })
+    }
+
+    pub fn set_max_future_drift(&mut self, drift: Duration) {
+        self.max_future_drift_seconds = drift.num_seconds();
+    }
+
+    // Build the next block on our tip from a batch of transactions, ready to be handed to a Miner (called locally with transactions from the mempool)
+    // The miner is paid the subsidy and the batch's fees through a coinbase put in front of the batch
+    pub fn next_block(&self, miner: &PeerId, transactions: Vec<Transaction>) -> Result<Block, BlockchainError> {
+        let current_block = self.latest_block();
+        let index = current_block.index() + 1;
+        let fees = transactions.iter().map(Transaction::fee).fold(0, CurrencyType::saturating_add);
+        let mut block_transactions = Vec::with_capacity(transactions.len() + 1);
+        block_transactions.push(Transaction::coinbase(miner.clone(), self.block_subsidy(index).saturating_add(fees), index));
+        block_transactions.extend(transactions);
+        let bits = self.current_target().to_compact();
+        let block = Block::new(block_transactions, index, current_block.hash(), bits);
+        self.is_valid_next_block(&block)?;
+
+        Ok(block)
     }
 
     pub fn get_balance(&self, peer_id: String) -> CurrencyType {
-        // println!("balances {:#?}", self.balances);
-        self.balances.get(&peer_id).map(|&bal| bal).unwrap_or(STARTING_BALANCE)
+        self.storage.account(&peer_id).balance
+    }
+
+    // The nonce the peer's next transaction has to use
+    pub fn next_nonce(&self, peer_id: String) -> u64 {
+        self.storage.account(&peer_id).nonce
     }
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    // Build the next block on the tip and search for a nonce right here (the targets used in tests are easy)
+    pub(crate) fn mine_next(blockchain: &mut Blockchain, miner: &PeerId) {
+        mine_with(blockchain, miner, Vec::new());
+    }
+
+    pub(crate) fn mine_with(blockchain: &mut Blockchain, miner: &PeerId, transactions: Vec<Transaction>) -> ChainUpdate {
+        let mut block = blockchain.next_block(miner, transactions).unwrap();
+        let mut nonce = 0;
+        while !block.meets_target() {
+            nonce += 1;
+            block.set_nonce(nonce);
+        }
+
+        blockchain.add_block(block).unwrap()
+    }
+
+    // Mine an empty block on the tip with the given timestamp
+    fn mine_at(blockchain: &mut Blockchain, timestamp: DateTime<Utc>) -> Result<ChainUpdate, BlockchainError> {
+        let mut block = blockchain.next_block(&PeerId::random(), Vec::new()).unwrap();
+        block.set_timestamp(timestamp);
+        let mut nonce = 0;
+        while !block.meets_target() {
+            nonce += 1;
+            block.set_nonce(nonce);
+        }
+
+        blockchain.add_block(block)
+    }
+
+    // Build and mine a block on any block we know about, not just the tip, so tests can grow side branches
+    fn mine_on(blockchain: &Blockchain, parent: &Block, transactions: Vec<Transaction>) -> Block {
+        let index = parent.index() + 1;
+        let fees = transactions.iter().map(Transaction::fee).sum::<CurrencyType>();
+        let mut block_transactions = vec![Transaction::coinbase(PeerId::random(), blockchain.block_subsidy(index) + fees, index)];
+        block_transactions.extend(transactions);
+        let bits = blockchain.next_target(&parent.hash()).to_compact();
+        let mut block = Block::new(block_transactions, index, parent.hash(), bits);
+        let mut nonce = 0;
+        while !block.meets_target() {
+            nonce += 1;
+            block.set_nonce(nonce);
+        }
+
+        block
+    }
+
+    // A chain where each keypair's peer id starts out with some money
+    pub(crate) fn funded_chain(keypairs: &[&Keypair], balance: CurrencyType) -> Blockchain {
+        let mut genesis = Genesis::default();
+        for keypair in keypairs {
+            genesis.allocations.insert(keypair.public().into_peer_id().to_string(), balance);
+        }
+
+        Blockchain::new(genesis, Target::from_compact(0x200fffff).unwrap())
+    }
+
+    // A signed transaction from the keypair's peer id to someone new
+    pub(crate) fn payment(keypair: &Keypair, amount: CurrencyType, fee: CurrencyType, nonce: u64) -> Transaction {
+        let mut transaction = Transaction::new(keypair.public().into_peer_id(), PeerId::random(), amount, fee, nonce);
+        transaction.sign(keypair).unwrap();
+
+        transaction
+    }
+
+    // A chain of a few blocks on an easy target
+    fn test_chain(blocks: u64) -> Blockchain {
+        let mut blockchain = Blockchain::new(Genesis::default(), Target::from_compact(0x200fffff).unwrap());
+        let miner = PeerId::random();
+        for _ in 0..blocks {
+            mine_next(&mut blockchain, &miner);
+        }
+
+        blockchain
+    }
+
+    // A copy of the main chain block at height with a different index, parent or target, mined again
+    fn rebuilt_block(blockchain: &Blockchain, height: u64, index: u64, previous_hash: Arc<BlockHash>, bits: u32) -> Block {
+        let original = blockchain.block_at_height(height).unwrap();
+        let mut block = Block::new(original.transactions().to_vec(), index, previous_hash, bits);
+        let mut nonce = 0;
+        while !block.meets_target() {
+            nonce += 1;
+            block.set_nonce(nonce);
+        }
+
+        block
+    }
+
+    // Swap the main chain block at height for another one behind the blockchain's back, as if storage had been edited
+    fn replace_block(blockchain: &mut Blockchain, height: u64, block: Block) {
+        let entry = blockchain.storage.block(&blockchain.storage.main_chain_hash(height).unwrap()).unwrap();
+        let mut connected = vec![*block.hash()];
+        connected.extend((height + 1..blockchain.storage.main_chain_length()).map(|later| blockchain.storage.main_chain_hash(later).unwrap()));
+        blockchain.storage.insert_block(BlockEntry { block, ..entry });
+        blockchain.storage.commit(StateUpdate { truncate_to: Some(height), connected, accounts: HashMap::new() });
+    }
+
+    fn validation_error(blockchain: &Blockchain) -> (usize, BlockchainError) {
+        let invalid = blockchain.validate().unwrap_err();
+        (invalid.position, invalid.error)
+    }
+
+    #[test]
+    fn validate_accepts_an_untouched_chain() {
+        assert!(test_chain(5).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_finds_a_tampered_hash() {
+        let mut blockchain = test_chain(5);
+        // Change the nonce without updating the hash that was stored with it
+        let mut json = serde_json::to_value(&blockchain.block_at_height(3).unwrap()).unwrap();
+        json["nonce"] = (json["nonce"].as_u64().unwrap() + 1).into();
+        let tampered: Block = serde_json::from_value(json).unwrap();
+        let entry = blockchain.storage.block(&tampered.hash()).unwrap();
+        blockchain.storage.insert_block(BlockEntry { block: tampered, ..entry });
+
+        assert_eq!(validation_error(&blockchain), (3, BlockchainError::MalformedBlock));
+    }
+
+    #[test]
+    fn validate_finds_wrong_bits() {
+        let mut blockchain = test_chain(5);
+        let previous_hash = blockchain.block_at_height(1).unwrap().hash();
+        let easier = rebuilt_block(&blockchain, 2, 2, previous_hash, 0x207fffff);
+        replace_block(&mut blockchain, 2, easier);
+
+        assert_eq!(validation_error(&blockchain), (2, BlockchainError::WrongBits { expected: 0x200fffff, found: 0x207fffff }));
+    }
+
+    #[test]
+    fn validate_finds_a_broken_link() {
+        let mut blockchain = test_chain(5);
+        // Block 4 claiming to follow block 2, skipping block 3
+        let previous_hash = blockchain.block_at_height(2).unwrap().hash();
+        let skipping = rebuilt_block(&blockchain, 4, 4, previous_hash, 0x200fffff);
+        replace_block(&mut blockchain, 4, skipping);
+
+        assert_eq!(validation_error(&blockchain), (4, BlockchainError::BadPreviousHash));
+    }
+
+    #[test]
+    fn validate_finds_a_wrong_index() {
+        let mut blockchain = test_chain(5);
+        let previous_hash = blockchain.block_at_height(2).unwrap().hash();
+        let misnumbered = rebuilt_block(&blockchain, 3, 7, previous_hash, 0x200fffff);
+        replace_block(&mut blockchain, 3, misnumbered);
+
+        assert_eq!(validation_error(&blockchain), (3, BlockchainError::WrongIndex { expected: 3, found: 7 }));
+    }
+
+    #[test]
+    fn replayed_and_skipped_nonces_are_rejected() {
+        let keypair = Keypair::generate_secp256k1();
+        let mut blockchain = funded_chain(&[&keypair], 100);
+        let first = payment(&keypair, 10, 1, 0);
+
+        assert_eq!(blockchain.check_transactions(&[first.clone(), first.clone()]), Err((1, BlockchainError::InvalidNonce { expected: 1, found: 0 })));
+        assert_eq!(blockchain.check_transactions(&[payment(&keypair, 10, 1, 1)]), Err((0, BlockchainError::InvalidNonce { expected: 0, found: 1 })));
+
+        // Once it's in a block the same transaction can't go in another one
+        mine_with(&mut blockchain, &PeerId::random(), vec![first.clone()]);
+        assert_eq!(blockchain.next_nonce(keypair.public().into_peer_id().to_string()), 1);
+        assert_eq!(blockchain.check_transactions(&[first]), Err((0, BlockchainError::InvalidNonce { expected: 1, found: 0 })));
+        assert_eq!(blockchain.check_transactions(&[payment(&keypair, 10, 1, 2)]), Err((0, BlockchainError::InvalidNonce { expected: 1, found: 2 })));
+        assert!(blockchain.check_transactions(&[payment(&keypair, 10, 1, 1)]).is_ok());
+    }
+
+    #[test]
+    fn reverting_a_transaction_restores_the_nonce() {
+        let keypair = Keypair::generate_secp256k1();
+        let sender = keypair.public().into_peer_id().to_string();
+        let blockchain = funded_chain(&[&keypair], 100);
+        let transaction = payment(&keypair, 10, 1, 0);
+
+        let mut changes = AccountChanges::new(blockchain.storage.as_ref());
+        Blockchain::apply_transaction(&mut changes, &transaction).unwrap();
+        assert_eq!(changes.get(&sender), Account { balance: 89, nonce: 1 });
+        Blockchain::revert_transaction(&mut changes, &transaction);
+        assert_eq!(changes.get(&sender), Account { balance: 100, nonce: 0 });
+        assert_eq!(changes.get(&transaction.receiver), Account::default());
+
+        // With the nonce back where it was, the transaction is the sender's next one again
+        assert!(Blockchain::apply_transaction(&mut changes, &transaction).is_ok());
+    }
+
+    #[test]
+    fn timestamps_have_to_be_after_the_median_time_past() {
+        let mut blockchain = test_chain(0);
+        let start = Utc::now() - Duration::hours(1);
+        for minutes in 1..=5 {
+            mine_at(&mut blockchain, start + Duration::minutes(minutes)).unwrap();
+        }
+
+        // The median of genesis and those five is the third block, the last two don't count for more than it
+        let median = start + Duration::minutes(3);
+        assert_eq!(mine_at(&mut blockchain, median).unwrap_err(), BlockchainError::TimestampTooOld { median, found: median });
+        // Older than its parent but still after the median is fine
+        assert!(mine_at(&mut blockchain, median + Duration::seconds(30)).is_ok());
+        assert!(blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn timestamps_cant_be_too_far_ahead() {
+        let mut blockchain = test_chain(0);
+        let too_new = Utc::now() + Duration::seconds(DEFAULT_MAX_FUTURE_DRIFT_SECONDS) + Duration::minutes(5);
+        assert!(matches!(mine_at(&mut blockchain, too_new), Err(BlockchainError::TimestampTooNew { found, .. }) if found == too_new));
+
+        // Fine with the default drift, but not once it's been tightened
+        let ahead = Utc::now() + Duration::minutes(90);
+        blockchain.set_max_future_drift(Duration::hours(1));
+        assert!(matches!(mine_at(&mut blockchain, ahead), Err(BlockchainError::TimestampTooNew { .. })));
+        blockchain.set_max_future_drift(Duration::seconds(DEFAULT_MAX_FUTURE_DRIFT_SECONDS));
+        assert!(mine_at(&mut blockchain, ahead).is_ok());
+    }
+
+    #[test]
+    fn first_retarget_ignores_genesis_timestamp() {
+        let initial_target = Target::from_compact(0x200fffff).unwrap();
+        let mut blockchain = Blockchain::new(Genesis::default(), initial_target);
+        let miner = PeerId::random();
+
+        // Blocks mined back to back, but the first interval starts at genesis so the target stays put
+        for _ in 1..blockchain.retarget_interval {
+            mine_next(&mut blockchain, &miner);
+        }
+        assert_eq!(blockchain.current_target(), initial_target);
+
+        // The next interval doesn't include genesis, so blocks that fast make the target harder
+        for _ in 0..blockchain.retarget_interval {
+            mine_next(&mut blockchain, &miner);
+        }
+        assert!(blockchain.current_target() < initial_target);
+        assert!(blockchain.validate().is_ok());
+    }
 
+    #[test]
+    fn blocks_on_time_keep_the_target() {
+        let genesis = Genesis { target_block_seconds: 60, retarget_interval: 5, ..Genesis::default() };
+        // The rules are part of the genesis block, so a network with other rules is a different network
+        assert_ne!(genesis.block().hash(), Genesis::default().block().hash());
+
+        let initial_target = Target::from_compact(0x200fffff).unwrap();
+        let mut blockchain = Blockchain::new(genesis, initial_target);
+        let start = Utc::now() - Duration::hours(1);
+        for height in 1..=20 {
+            mine_at(&mut blockchain, start + Duration::minutes(height)).unwrap();
+            assert_eq!(blockchain.current_target(), initial_target);
+        }
+        assert!(blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn reorg_reverts_balances_and_nonces() {
+        let sender = Keypair::generate_secp256k1();
+        let peer_id = sender.public().into_peer_id().to_string();
+        let mut blockchain = funded_chain(&[&sender], 100);
+        let genesis = blockchain.block_at_height(0).unwrap();
+        mine_with(&mut blockchain, &PeerId::random(), vec![payment(&sender, 30, 1, 0)]);
+        assert_eq!((blockchain.get_balance(peer_id.clone()), blockchain.next_nonce(peer_id.clone())), (69, 1));
+
+        // A branch with as much work as ours isn't switched to, one with more is
+        let first = mine_on(&blockchain, &genesis, Vec::new());
+        assert!(blockchain.add_block(first.clone()).unwrap().connected.is_empty());
+        let update = blockchain.add_block(mine_on(&blockchain, &first, Vec::new())).unwrap();
+        assert_eq!((update.disconnected.len(), update.connected.len()), (1, 2));
+
+        // The payment was only in the block that got disconnected, so it never happened
+        assert_eq!((blockchain.get_balance(peer_id.clone()), blockchain.next_nonce(peer_id)), (100, 0));
+        assert!(blockchain.validate().is_ok());
+        mine_with(&mut blockchain, &PeerId::random(), vec![payment(&sender, 30, 1, 0)]);
+    }
+
+    #[test]
+    fn invalid_heavier_branch_is_discarded() {
+        let mut blockchain = test_chain(1);
+        let genesis = blockchain.block_at_height(0).unwrap();
+        let tip = blockchain.latest_block().clone();
+
+        // Side branches only get their transactions checked once they have more work than the main chain
+        let broke = Keypair::generate_secp256k1();
+        let first = mine_on(&blockchain, &genesis, vec![payment(&broke, 10, 0, 0)]);
+        assert!(blockchain.add_block(first.clone()).is_ok());
+        let second = mine_on(&blockchain, &first, Vec::new());
+        assert_eq!(blockchain.add_block(second.clone()).unwrap_err(), BlockchainError::InsufficientFunds { balance: 0, amount: 10 });
+
+        // The old tip stays and the whole branch from the invalid block up is forgotten
+        assert_eq!(blockchain.latest_block(), &tip);
+        assert!(!blockchain.has_block(&first.hash()));
+        assert!(!blockchain.has_block(&second.hash()));
+        assert!(blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn deep_forks_are_rejected_and_pruned() {
+        let mut blockchain = test_chain(0);
+        let genesis = blockchain.block_at_height(0).unwrap();
+        // Space the blocks out as intended so the target doesn't get harder as the chain grows
+        let block_time = Duration::seconds(blockchain.target_block_seconds);
+        let start = Utc::now() - block_time * (MAX_FORK_DEPTH as i32 + 2);
+        for height in 1..=MAX_FORK_DEPTH {
+            mine_at(&mut blockchain, start + block_time * height as i32).unwrap();
+        }
+
+        // Genesis is as deep as a fork can be
+        let first = mine_on(&blockchain, &genesis, Vec::new());
+        assert!(blockchain.add_block(first.clone()).is_ok());
+        let second = mine_on(&blockchain, &first, Vec::new());
+
+        // One more block and that branch can never catch up, so it's dropped and nothing new can fork from genesis
+        mine_at(&mut blockchain, start + block_time * (MAX_FORK_DEPTH as i32 + 1)).unwrap();
+        assert!(!blockchain.has_block(&first.hash()));
+        assert_eq!(blockchain.add_block(second).unwrap_err(), BlockchainError::UnknownParent);
+        assert_eq!(
+            blockchain.add_block(mine_on(&blockchain, &genesis, Vec::new())).unwrap_err(),
+            BlockchainError::ForkTooDeep { fork_height: 0, tip_height: MAX_FORK_DEPTH + 1 },
+        );
+        let parent = blockchain.block_at_height(1).unwrap();
+        assert!(blockchain.add_block(mine_on(&blockchain, &parent, Vec::new())).is_ok());
+    }
+
+    // The next block on the tip with the given coinbase in front of the transactions, not mined
+    fn paid_with(blockchain: &Blockchain, coinbase: Transaction, transactions: Vec<Transaction>) -> Block {
+        let mut block_transactions = vec![coinbase];
+        block_transactions.extend(transactions);
+
+        Block::new(block_transactions, blockchain.height() + 1, blockchain.latest_block().hash(), blockchain.current_target().to_compact())
+    }
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        let halving = Blockchain::new(Genesis { block_subsidy: 50, halving_interval: Some(10), ..Genesis::default() }, Target::MAX);
+        let subsidies: Vec<CurrencyType> = [1, 9, 10, 19, 20, 30, 50, 60].iter().map(|&index| halving.block_subsidy(index)).collect();
+        assert_eq!(subsidies, vec![50, 50, 25, 25, 12, 6, 1, 0]);
+        // Long past the point where every bit has been shifted out
+        assert_eq!(halving.block_subsidy(u64::max_value()), 0);
+
+        let constant = Blockchain::new(Genesis { block_subsidy: 50, halving_interval: None, ..Genesis::default() }, Target::MAX);
+        assert_eq!(constant.block_subsidy(u64::max_value()), 50);
+        // The subsidy is one of the rules every node has to agree on
+        assert_ne!(halving.genesis.block().hash(), constant.genesis.block().hash());
+    }
+
+    #[test]
+    fn coinbase_pays_the_subsidy_and_fees() {
+        let sender = Keypair::generate_secp256k1();
+        let mut blockchain = funded_chain(&[&sender], 100);
+        let miner = PeerId::random();
+        mine_with(&mut blockchain, &miner, vec![payment(&sender, 10, 3, 0), payment(&sender, 10, 4, 1)]);
+        assert_eq!(blockchain.get_balance(miner.to_string()), blockchain.block_subsidy(1) + 7);
+        assert_eq!(blockchain.get_balance(sender.public().into_peer_id().to_string()), 100 - 27);
+
+        // Paying themself a coin too many or too few, or forgetting the fees
+        let index = blockchain.height() + 1;
+        let expected = blockchain.block_subsidy(index) + 5;
+        for &found in &[expected + 1, expected - 1, expected - 5] {
+            let block = paid_with(&blockchain, Transaction::coinbase(miner.clone(), found, index), vec![payment(&sender, 10, 5, 2)]);
+            assert_eq!(blockchain.is_valid_next_block(&block).unwrap_err(), BlockchainError::WrongReward { expected, found });
+        }
+        let block = paid_with(&blockchain, Transaction::coinbase(miner.clone(), expected, index), vec![payment(&sender, 10, 5, 2)]);
+        assert!(blockchain.is_valid_next_block(&block).is_ok());
+    }
+
+    #[test]
+    fn coinbase_has_to_be_unsigned_and_numbered_by_the_block() {
+        let blockchain = test_chain(1);
+        let index = blockchain.height() + 1;
+        let reward = blockchain.block_subsidy(index);
+
+        let mut with_fee = Transaction::coinbase(PeerId::random(), reward, index);
+        with_fee.fee = 1;
+        let mut signed = Transaction::coinbase(PeerId::random(), reward, index);
+        signed.signature = vec![1, 2, 3];
+        let reused = Transaction::coinbase(PeerId::random(), reward, index - 1);
+        for coinbase in vec![with_fee, signed, reused] {
+            assert_eq!(blockchain.is_valid_next_block(&paid_with(&blockchain, coinbase, Vec::new())).unwrap_err(), BlockchainError::InvalidCoinbase);
+        }
+
+        // A block has to start with one
+        let sender = Keypair::generate_secp256k1();
+        let block = Block::new(vec![payment(&sender, 10, 0, 0)], index, blockchain.latest_block().hash(), blockchain.current_target().to_compact());
+        assert_eq!(blockchain.is_valid_next_block(&block).unwrap_err(), BlockchainError::MissingCoinbase);
+    }
+}
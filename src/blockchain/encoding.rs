@@ -0,0 +1,379 @@
+// Local imports
+use crate::blockchain::HASH_SIZE;
+// Std imports
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+// External imports
+use libp2p::PeerId;
+
+// Canonical binary format for everything we hash, gossip and store (JSON is only for people to read)
+// Fixed size fields are little endian, integers that are usually small are varints (LEB128),
+// and anything variable sized is prefixed with its length as a varint
+// There's exactly one encoding of any value, so decoding and re-encoding always gives back the same bytes
+
+// Peer ids are stored as their raw multihash rather than the much longer base58 string
+const PEER_ID_STRING_TAG: u8 = 0;
+const PEER_ID_BYTES_TAG: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    // Ran out of bytes part way through a value
+    UnexpectedEnd,
+    // There were bytes left over after the value
+    TrailingBytes(usize),
+    // A varint that's too big or has a shorter encoding
+    InvalidVarint,
+    // An enum tag we don't know
+    InvalidTag(u8),
+    // A string that isn't UTF-8
+    InvalidUtf8,
+    // Peer id bytes that aren't a peer id, or a plain string that should have been stored as one
+    InvalidPeerId,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::TrailingBytes(count) => write!(f, "{} unexpected bytes after the end", count),
+            DecodeError::InvalidVarint => write!(f, "invalid varint"),
+            DecodeError::InvalidTag(tag) => write!(f, "unknown tag {}", tag),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::InvalidPeerId => write!(f, "invalid peer id"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+pub trait Encode {
+    fn encode_to(&self, bytes: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.encode_to(&mut bytes);
+
+        bytes
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError>;
+
+    // Decode a value that takes up all of the bytes
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode_from(&mut reader)?;
+        reader.finish()?;
+
+        Ok(value)
+    }
+}
+
+pub fn put_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// Length prefixed bytes
+pub fn put_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    put_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+// A peer id string, as its bytes if it's a valid peer id (anything else is kept as is so it can still be hashed and rejected)
+pub fn put_peer_id(bytes: &mut Vec<u8>, peer_id: &str) {
+    match PeerId::from_str(peer_id) {
+        Ok(parsed) if parsed.to_base58() == peer_id => {
+            bytes.push(PEER_ID_BYTES_TAG);
+            put_bytes(bytes, parsed.as_bytes());
+        },
+        _ => {
+            bytes.push(PEER_ID_STRING_TAG);
+            put_bytes(bytes, peer_id.as_bytes());
+        },
+    }
+}
+
+// Reads values back out of encoded bytes, front to back
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Make sure nothing is left over
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            count => Err(DecodeError::TrailingBytes(count)),
+        }
+    }
+
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < count {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, DecodeError> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub fn hash(&mut self) -> Result<[u8; HASH_SIZE], DecodeError> {
+        Ok(self.take(HASH_SIZE)?.try_into().unwrap())
+    }
+
+    pub fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            let bits = (byte & 0x7f) as u64;
+            // The last byte of a u64 only has one bit to give
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                // A zero last byte means the same value had a shorter encoding
+                if byte == 0 && shift > 0 {
+                    return Err(DecodeError::InvalidVarint);
+                }
+                return Ok(value);
+            }
+        }
+
+        Err(DecodeError::InvalidVarint)
+    }
+
+    // A length prefix, which can't be more than what's left
+    pub fn length(&mut self) -> Result<usize, DecodeError> {
+        let length = self.varint()?;
+        if length > self.remaining() as u64 {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+
+        Ok(length as usize)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = self.length()?;
+        self.take(length)
+    }
+
+    pub fn string(&mut self) -> Result<String, DecodeError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub fn peer_id(&mut self) -> Result<String, DecodeError> {
+        match self.u8()? {
+            PEER_ID_BYTES_TAG => {
+                let bytes = self.bytes()?;
+                PeerId::from_bytes(bytes.to_vec()).map(|peer_id| peer_id.to_base58()).map_err(|_| DecodeError::InvalidPeerId)
+            },
+            PEER_ID_STRING_TAG => {
+                let string = self.string()?;
+                // Valid peer ids always go in as bytes, otherwise the same transaction could be encoded two ways
+                match PeerId::from_str(&string) {
+                    Ok(parsed) if parsed.to_base58() == string => Err(DecodeError::InvalidPeerId),
+                    _ => Ok(string),
+                }
+            },
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for [u8; HASH_SIZE] {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(self);
+    }
+}
+
+impl Decode for [u8; HASH_SIZE] {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        reader.hash()
+    }
+}
+
+// Lists are prefixed with how many items they have
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        put_varint(bytes, self.len() as u64);
+        for item in self.iter() {
+            item.encode_to(bytes);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        // Every item takes at least a byte, so a bogus count can't make us allocate more than the input
+        let count = reader.length()?;
+        let mut items = Vec::with_capacity(count);
+        for _ in 0..count {
+            items.push(T::decode_from(reader)?);
+        }
+
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Block, BlockHeader, Transaction};
+    use crate::message::{Announce, NetworkMessage};
+    use crate::sync::{ChainTip, SyncRequest, SyncResponse};
+    use chrono::Utc;
+    use libp2p::identity::Keypair;
+    use std::sync::Arc;
+
+    fn signed_transaction(nonce: u64) -> Transaction {
+        let keypair = Keypair::generate_secp256k1();
+        let sender = PeerId::from_public_key(keypair.public());
+        let mut transaction = Transaction::new(sender, PeerId::random(), 250, 1, nonce);
+        transaction.sign(&keypair).unwrap();
+
+        transaction
+    }
+
+    fn block() -> Block {
+        let mut transactions = vec![Transaction::coinbase(PeerId::random(), 55, 7)];
+        transactions.extend((0..5).map(signed_transaction));
+        let mut block = Block::new(transactions, 7, Arc::new([3u8; HASH_SIZE]), 0x1f00ffff);
+        block.set_nonce(123_456);
+
+        block
+    }
+
+    fn round_trip<T: Encode + Decode + PartialEq + fmt::Debug>(value: T) {
+        let bytes = value.encode();
+        let decoded = T::decode(&bytes).unwrap();
+        assert_eq!(decoded, value);
+        assert_eq!(decoded.encode(), bytes);
+    }
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        let block = block();
+        let block_json = serde_json::to_vec(&block).unwrap().len();
+        let block_binary = block.encode().len();
+        assert!(block_binary * 2 < block_json, "block: {} bytes binary, {} bytes JSON", block_binary, block_json);
+
+        let transaction = signed_transaction(0);
+        let transaction_json = serde_json::to_vec(&transaction).unwrap().len();
+        let transaction_binary = transaction.encode().len();
+        assert!(transaction_binary * 2 < transaction_json, "transaction: {} bytes binary, {} bytes JSON", transaction_binary, transaction_json);
+
+        // Headers are exactly the bytes that get hashed
+        assert_eq!(block.header().encode().len(), crate::blockchain::block::HEADER_SIZE);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let block = block();
+        round_trip(block.clone());
+        round_trip(signed_transaction(42));
+        round_trip(Transaction::coinbase(PeerId::random(), 50, 0));
+        round_trip(block.header());
+        round_trip::<BlockHeader>(Block::genesis(Utc::now(), [3u8; HASH_SIZE], Vec::new()).header());
+
+        round_trip(SyncRequest::GetTip);
+        round_trip(SyncRequest::GetHeaders { locator: vec![[1u8; HASH_SIZE], [2u8; HASH_SIZE]], max: 500 });
+        round_trip(SyncRequest::GetBlocksByRange { start: 1_000, count: 16 });
+        round_trip(SyncRequest::GetBlockByHash([9u8; HASH_SIZE]));
+        let tip = ChainTip { height: 300, hash: [4u8; HASH_SIZE], total_work: u128::max_value() };
+        round_trip(SyncResponse::Tip(tip.clone()));
+        round_trip(SyncResponse::Headers(vec![block.header(), block.header()]));
+        round_trip(SyncResponse::Blocks(vec![block.clone()]));
+        round_trip(SyncResponse::Block(block.clone()));
+        round_trip(SyncResponse::NotFound([5u8; HASH_SIZE]));
+
+        let messages = vec![
+            NetworkMessage::NewBlock(block),
+            NetworkMessage::NewTransaction(signed_transaction(3)),
+            NetworkMessage::Announce(Announce { peer_id: PeerId::random().to_string(), tip, timestamp: Utc::now() }),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes();
+            assert_eq!(NetworkMessage::from_bytes(&bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn varints_must_be_minimal() {
+        assert_eq!(Reader::new(&[0x7f]).varint(), Ok(0x7f));
+        assert_eq!(Reader::new(&[0x80, 0x01]).varint(), Ok(0x80));
+        // 0 and 1 padded out with a continuation byte
+        assert_eq!(Reader::new(&[0x80, 0x00]).varint(), Err(DecodeError::InvalidVarint));
+        assert_eq!(Reader::new(&[0x81, 0x00]).varint(), Err(DecodeError::InvalidVarint));
+        // Past 64 bits
+        let mut too_big = vec![0xff; 9];
+        too_big.push(0x02);
+        assert_eq!(Reader::new(&too_big).varint(), Err(DecodeError::InvalidVarint));
+        let mut max = Vec::new();
+        put_varint(&mut max, u64::max_value());
+        assert_eq!(Reader::new(&max).varint(), Ok(u64::max_value()));
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut bytes = signed_transaction(0).encode();
+        bytes.push(0);
+        assert_eq!(Transaction::decode(&bytes), Err(DecodeError::TrailingBytes(1)));
+
+        let mut bytes = block().encode();
+        bytes.extend_from_slice(&[1, 2]);
+        assert_eq!(Block::decode(&bytes), Err(DecodeError::TrailingBytes(2)));
+    }
+
+    #[test]
+    fn peer_ids_have_one_encoding() {
+        let peer_id = PeerId::random().to_string();
+        let mut bytes = Vec::new();
+        put_peer_id(&mut bytes, &peer_id);
+        assert_eq!(bytes[0], PEER_ID_BYTES_TAG);
+        assert_eq!(Reader::new(&bytes).peer_id(), Ok(peer_id.clone()));
+
+        // The same peer id sent as a plain string would give the same transaction a second encoding
+        let mut as_string = vec![PEER_ID_STRING_TAG];
+        put_bytes(&mut as_string, peer_id.as_bytes());
+        assert_eq!(Reader::new(&as_string).peer_id(), Err(DecodeError::InvalidPeerId));
+
+        // Strings that aren't peer ids (like the coinbase's empty sender) still round trip
+        let mut empty = Vec::new();
+        put_peer_id(&mut empty, "");
+        assert_eq!(empty, vec![PEER_ID_STRING_TAG, 0]);
+        assert_eq!(Reader::new(&empty).peer_id(), Ok(String::new()));
+    }
+}
@@ -0,0 +1,99 @@
+// Std imports
+use std::error::Error;
+use std::fmt;
+// Local imports
+use crate::blockchain::transaction::CurrencyType;
+// External imports
+use chrono::{DateTime, Utc};
+
+// Everything that can go wrong when adding to or validating the blockchain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockchainError {
+    // The block doesn't point at the block before it
+    BadPreviousHash,
+    // The block builds on a block we don't know about
+    UnknownParent,
+    // We already have this block
+    AlreadyKnown,
+    // The block's index isn't the next one in the chain
+    WrongIndex { expected: u64, found: u64 },
+    // The block's branch splits off from the main chain too far below the tip to ever be switched to
+    ForkTooDeep { fork_height: u64, tip_height: u64 },
+    // The block's header has a different target than the one the chain expects
+    WrongBits { expected: u32, found: u32 },
+    // The hash is above the block's target
+    InsufficientDifficulty,
+    // The block isn't newer than the median time of the blocks before it
+    TimestampTooOld { median: DateTime<Utc>, found: DateTime<Utc> },
+    // The block claims to be from too far in the future
+    TimestampTooNew { latest: DateTime<Utc>, found: DateTime<Utc> },
+    // The sender is trying to spend more than they have
+    InsufficientFunds { balance: CurrencyType, amount: CurrencyType },
+    // A coinbase transaction showed up somewhere other than the start of a block
+    UnexpectedCoinbase,
+    // The block doesn't start with a coinbase transaction
+    MissingCoinbase,
+    // The coinbase transaction isn't shaped like one (signed, has a fee or doesn't use the block's index as its nonce)
+    InvalidCoinbase,
+    // The coinbase pays out something other than the subsidy plus the block's fees
+    WrongReward { expected: CurrencyType, found: CurrencyType },
+    // The sender and receiver are the same peer
+    SelfTransfer,
+    // Sending nothing isn't a transaction
+    ZeroAmount,
+    // The sender or receiver isn't a valid peer id
+    InvalidPeerId(String),
+    // The transaction wasn't signed by the sender
+    MissingSignature,
+    // The signature doesn't match the transaction or the sender's public key
+    InvalidSignature,
+    // The transaction isn't the sender's next one (replayed or out of order)
+    InvalidNonce { expected: u64, found: u64 },
+    // The block's contents don't match its hash
+    MalformedBlock,
+    // The block's merkle root doesn't match its transactions
+    BadMerkleRoot,
+    // The block holds more transactions than allowed
+    TooManyTransactions { max: usize, found: usize },
+    // The first block isn't our genesis block
+    WrongGenesis,
+    // The mempool is full of transactions with a higher priority
+    MempoolFull,
+    // Replaying every block doesn't produce the stored balances and nonces
+    BalanceMismatch,
+}
+
+impl fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockchainError::BadPreviousHash => write!(f, "block does not extend the previous block"),
+            BlockchainError::UnknownParent => write!(f, "block extends a block we do not have"),
+            BlockchainError::AlreadyKnown => write!(f, "block is already known"),
+            BlockchainError::WrongIndex { expected, found } => write!(f, "expected block index {} but found {}", expected, found),
+            BlockchainError::ForkTooDeep { fork_height, tip_height } => write!(f, "block forks from height {} which is too far below the tip at {}", fork_height, tip_height),
+            BlockchainError::WrongBits { expected, found } => write!(f, "expected block bits {:#010x} but found {:#010x}", expected, found),
+            BlockchainError::InsufficientDifficulty => write!(f, "block hash does not meet the difficulty"),
+            BlockchainError::TimestampTooOld { median, found } => write!(f, "block timestamp {} is not after the median {}", found, median),
+            BlockchainError::TimestampTooNew { latest, found } => write!(f, "block timestamp {} is after the latest allowed {}", found, latest),
+            BlockchainError::InsufficientFunds { balance, amount } => write!(f, "tried to send ${} with a balance of ${}", amount, balance),
+            BlockchainError::UnexpectedCoinbase => write!(f, "coinbase transaction is not the first in a block"),
+            BlockchainError::MissingCoinbase => write!(f, "block does not start with a coinbase transaction"),
+            BlockchainError::InvalidCoinbase => write!(f, "coinbase transaction is malformed"),
+            BlockchainError::WrongReward { expected, found } => write!(f, "expected a block reward of ${} but found ${}", expected, found),
+            BlockchainError::SelfTransfer => write!(f, "sender and receiver are the same"),
+            BlockchainError::ZeroAmount => write!(f, "transaction amount must be greater than zero"),
+            BlockchainError::InvalidPeerId(peer_id) => write!(f, "{:?} is not a valid peer id", peer_id),
+            BlockchainError::MissingSignature => write!(f, "transaction is not signed"),
+            BlockchainError::InvalidSignature => write!(f, "transaction signature is not from the sender"),
+            BlockchainError::InvalidNonce { expected, found } => write!(f, "expected transaction nonce {} but found {}", expected, found),
+            BlockchainError::MalformedBlock => write!(f, "block hash does not match its contents"),
+            BlockchainError::BadMerkleRoot => write!(f, "block merkle root does not match its transactions"),
+            BlockchainError::TooManyTransactions { max, found } => write!(f, "block has {} transactions but the limit is {}", found, max),
+            BlockchainError::MempoolFull => write!(f, "mempool is full"),
+            BlockchainError::WrongGenesis => write!(f, "chain does not start with the genesis block"),
+            BlockchainError::BalanceMismatch => write!(f, "balances do not match the blocks in the chain"),
+        }
+    }
+}
+
+impl Error for BlockchainError {}
@@ -0,0 +1,124 @@
+// Local imports
+use crate::blockchain::{put_varint, Block, BlockchainError, CurrencyType, Transaction, HASH_SIZE};
+// Std imports
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+// External imports
+use chrono::{DateTime, Utc};
+use libp2p::PeerId;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+// Used when a network doesn't pick its own genesis time
+const DEFAULT_GENESIS_TIMESTAMP: &'static str = "2020-10-11T08:49:15Z";
+// How often we aim to mine a block by default
+const DEFAULT_TARGET_BLOCK_SECONDS: u64 = 30;
+// How many blocks go by between difficulty changes by default
+const DEFAULT_RETARGET_INTERVAL: u64 = 10;
+// New money paid to the miner of each block by default
+const DEFAULT_BLOCK_SUBSIDY: CurrencyType = 50;
+// How many blocks go by before the subsidy halves by default
+const DEFAULT_HALVING_INTERVAL: u64 = 10_000;
+
+// How a network starts out, every node on it has to load the same one (it's hashed into the genesis block)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Genesis {
+    pub timestamp: DateTime<Utc>,
+    // Starting balance of each peer id, everyone else starts with nothing
+    // Kept sorted so every node builds the genesis block the same way
+    #[serde(default)]
+    pub allocations: BTreeMap<String, CurrencyType>,
+    // How often we aim to mine a block, in seconds
+    #[serde(default = "default_target_block_seconds")]
+    pub target_block_seconds: u64,
+    // How many blocks go by between target adjustments
+    #[serde(default = "default_retarget_interval")]
+    pub retarget_interval: u64,
+    // New money paid to the miner of the first block
+    #[serde(default = "default_block_subsidy")]
+    pub block_subsidy: CurrencyType,
+    // How many blocks go by before the subsidy halves (null to keep it the same forever)
+    #[serde(default = "default_halving_interval")]
+    pub halving_interval: Option<u64>,
+}
+
+fn default_target_block_seconds() -> u64 {
+    DEFAULT_TARGET_BLOCK_SECONDS
+}
+
+fn default_retarget_interval() -> u64 {
+    DEFAULT_RETARGET_INTERVAL
+}
+
+fn default_block_subsidy() -> CurrencyType {
+    DEFAULT_BLOCK_SUBSIDY
+}
+
+fn default_halving_interval() -> Option<u64> {
+    Some(DEFAULT_HALVING_INTERVAL)
+}
+
+impl Genesis {
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    // Every allocation has to go to a real peer id or the money is lost
+    pub fn validate(&self) -> Result<(), BlockchainError> {
+        for peer_id in self.allocations.keys() {
+            if PeerId::from_str(peer_id).is_err() {
+                return Err(BlockchainError::InvalidPeerId(peer_id.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Hash of the rules every node on the network has to agree on (besides the allocations)
+    fn rules_hash(&self) -> [u8; HASH_SIZE] {
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, self.target_block_seconds);
+        put_varint(&mut bytes, self.retarget_interval);
+        put_varint(&mut bytes, self.block_subsidy);
+        // Never halving is written as 0, which isn't an interval anyone can use
+        put_varint(&mut bytes, self.halving_interval.unwrap_or(0));
+
+        Sha256::digest(&bytes).into()
+    }
+
+    // The first block of the chain, holding one unsigned transaction per allocation so its hash covers all of them
+    // It has no parent, so its previous hash is the hash of the rules instead and networks with different rules never mix
+    pub fn block(&self) -> Block {
+        let transactions = self.allocations.iter()
+            .map(|(peer_id, &balance)| Transaction {
+                sender: String::new(),
+                receiver: peer_id.clone(),
+                amount: balance,
+                fee: 0,
+                nonce: 0,
+                signature: Vec::new(),
+            })
+            .collect();
+
+        Block::genesis(self.timestamp, self.rules_hash(), transactions)
+    }
+
+    // Balances before any block is mined
+    pub fn balances(&self) -> HashMap<String, CurrencyType> {
+        self.allocations.iter().map(|(peer_id, &balance)| (peer_id.clone(), balance)).collect()
+    }
+}
+
+// A network with no allocations, where money only comes from block rewards, using the default rules
+impl Default for Genesis {
+    fn default() -> Self {
+        Self {
+            timestamp: DateTime::parse_from_rfc3339(DEFAULT_GENESIS_TIMESTAMP).unwrap().with_timezone(&Utc),
+            allocations: BTreeMap::new(),
+            target_block_seconds: DEFAULT_TARGET_BLOCK_SECONDS,
+            retarget_interval: DEFAULT_RETARGET_INTERVAL,
+            block_subsidy: DEFAULT_BLOCK_SUBSIDY,
+            halving_interval: Some(DEFAULT_HALVING_INTERVAL),
+        }
+    }
+}
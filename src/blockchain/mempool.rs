@@ -0,0 +1,267 @@
+// Local imports
+use crate::blockchain::{Blockchain, BlockchainError, CurrencyType, Transaction, HASH_SIZE};
+// Std imports
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Most pending transactions we hold on to at once
+pub const MAX_MEMPOOL_SIZE: usize = 5000;
+
+// A transaction waiting to be put in a block
+#[derive(Debug, Clone)]
+struct PendingTransaction {
+    transaction: Transaction,
+    // Order the transaction arrived in
+    arrival: u64,
+}
+
+impl PendingTransaction {
+    // Higher priority transactions are put in blocks first and evicted last (higher fees go first, then earlier arrivals)
+    fn priority(&self) -> (CurrencyType, Reverse<u64>) {
+        (self.transaction.fee, Reverse(self.arrival))
+    }
+}
+
+// Validated transactions (local and from peers) that haven't made it into a block yet
+#[derive(Debug)]
+pub struct Mempool {
+    transactions: HashMap<[u8; HASH_SIZE], PendingTransaction>,
+    max_size: usize,
+    next_arrival: u64,
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            transactions: HashMap::new(),
+            max_size,
+            next_arrival: 0,
+        }
+    }
+
+    // Validate a transaction against the chain (after the sender's other pending transactions) and add it
+    // Returns false if we already had the transaction
+    pub fn insert(&mut self, transaction: Transaction, blockchain: &Blockchain) -> Result<bool, BlockchainError> {
+        let hash = transaction.hash();
+        if self.transactions.contains_key(&hash) {
+            return Ok(false);
+        }
+
+        // Only one pending transaction per sender nonce, the first one we saw wins
+        let mut queue = self.sender_queue(&transaction.sender);
+        if queue.iter().any(|pending| pending.nonce == transaction.nonce) {
+            return Err(BlockchainError::InvalidNonce { expected: self.next_nonce(&transaction.sender, blockchain), found: transaction.nonce });
+        }
+        queue.retain(|pending| pending.nonce < transaction.nonce);
+        queue.push(transaction.clone());
+        blockchain.check_transactions(&queue).map_err(|(_, error)| error)?;
+
+        let pending = PendingTransaction { transaction, arrival: self.next_arrival };
+        // Make room by dropping the lowest priority transaction, unless the new one is lower still
+        if self.transactions.len() >= self.max_size {
+            match self.lowest_priority_evictable(&pending.transaction.sender) {
+                Some(lowest) if self.transactions[&lowest].priority() < pending.priority() => {
+                    self.transactions.remove(&lowest);
+                },
+                _ => return Err(BlockchainError::MempoolFull),
+            }
+        }
+        self.next_arrival += 1;
+        self.transactions.insert(hash, pending);
+
+        Ok(true)
+    }
+
+    // The lowest priority transaction that can be dropped without stranding any others (the last in a sender's queue)
+    fn lowest_priority_evictable(&self, excluded_sender: &str) -> Option<[u8; HASH_SIZE]> {
+        let mut last_per_sender: HashMap<&str, (&[u8; HASH_SIZE], &PendingTransaction)> = HashMap::new();
+        for (hash, pending) in self.transactions.iter() {
+            let sender = pending.transaction.sender.as_str();
+            if sender == excluded_sender {
+                continue;
+            }
+            match last_per_sender.get(sender) {
+                Some((_, last)) if last.transaction.nonce > pending.transaction.nonce => {},
+                _ => { last_per_sender.insert(sender, (hash, pending)); },
+            }
+        }
+
+        last_per_sender.values()
+            .min_by_key(|(_, pending)| pending.priority())
+            .map(|(hash, _)| **hash)
+    }
+
+    // Drop transactions that made it into a block or are no longer valid (called after the chain changes)
+    pub fn prune(&mut self, blockchain: &Blockchain) {
+        let senders: HashSet<String> = self.transactions.values().map(|pending| pending.transaction.sender.clone()).collect();
+        for sender in senders {
+            let next_nonce = blockchain.next_nonce(sender.clone());
+            let queue = self.sender_queue(&sender);
+            // Anything below the chain's nonce was already included (or can never be)
+            let (stale, remaining): (Vec<Transaction>, Vec<Transaction>) = queue.into_iter().partition(|pending| pending.nonce < next_nonce);
+            // A transaction that fails takes every later one from the same sender with it
+            let invalid = match blockchain.check_transactions(&remaining) {
+                Ok(()) => &remaining[remaining.len()..],
+                Err((position, _)) => &remaining[position..],
+            };
+            for transaction in stale.iter().chain(invalid.iter()) {
+                self.transactions.remove(&transaction.hash());
+            }
+        }
+    }
+
+    // Pull a batch of transactions for the next block, highest priority first while keeping each sender's nonce order
+    pub fn select_batch(&self, max_transactions: usize) -> Vec<Transaction> {
+        let mut sorted: Vec<&PendingTransaction> = self.transactions.values().collect();
+        sorted.sort_by_key(|pending| pending.transaction.nonce);
+        let mut queues: HashMap<&str, VecDeque<&PendingTransaction>> = HashMap::new();
+        for pending in sorted {
+            queues.entry(pending.transaction.sender.as_str()).or_default().push_back(pending);
+        }
+
+        let mut batch = Vec::new();
+        while batch.len() < max_transactions {
+            // The best transaction we can take is the best one at the front of any sender's queue
+            let best_sender = queues.iter()
+                .filter_map(|(sender, queue)| queue.front().map(|pending| (*sender, pending.priority())))
+                .max_by_key(|(_, priority)| *priority)
+                .map(|(sender, _)| sender);
+            match best_sender {
+                Some(sender) => batch.push(queues.get_mut(sender).unwrap().pop_front().unwrap().transaction.clone()),
+                None => break,
+            }
+        }
+
+        batch
+    }
+
+    // The nonce the sender's next transaction should use, counting the ones still waiting in the mempool
+    pub fn next_nonce(&self, sender: &str, blockchain: &Blockchain) -> u64 {
+        self.transactions.values()
+            .filter(|pending| pending.transaction.sender == sender)
+            .map(|pending| pending.transaction.nonce + 1)
+            .max()
+            .unwrap_or_else(|| blockchain.next_nonce(sender.to_string()))
+    }
+
+    // The sender's pending transactions in nonce order
+    fn sender_queue(&self, sender: &str) -> Vec<Transaction> {
+        let mut queue: Vec<Transaction> = self.transactions.values()
+            .filter(|pending| pending.transaction.sender == sender)
+            .map(|pending| pending.transaction.clone())
+            .collect();
+        queue.sort_by_key(|transaction| transaction.nonce);
+
+        queue
+    }
+
+    pub fn contains(&self, hash: &[u8; HASH_SIZE]) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MAX_MEMPOOL_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::blockchain::tests::{funded_chain, mine_with, payment};
+    use libp2p::PeerId;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn duplicates_are_ignored() {
+        let keypair = Keypair::generate_secp256k1();
+        let blockchain = funded_chain(&[&keypair], 100);
+        let mut mempool = Mempool::default();
+        let transaction = payment(&keypair, 10, 1, 0);
+
+        assert_eq!(mempool.insert(transaction.clone(), &blockchain), Ok(true));
+        assert_eq!(mempool.insert(transaction.clone(), &blockchain), Ok(false));
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&transaction.hash()));
+    }
+
+    #[test]
+    fn one_transaction_per_sender_and_nonce() {
+        let keypair = Keypair::generate_secp256k1();
+        let blockchain = funded_chain(&[&keypair], 100);
+        let mut mempool = Mempool::default();
+        let first = payment(&keypair, 10, 1, 0);
+
+        assert_eq!(mempool.insert(first.clone(), &blockchain), Ok(true));
+        // A different transaction with the same nonce doesn't replace the first
+        assert_eq!(mempool.insert(payment(&keypair, 20, 5, 0), &blockchain), Err(BlockchainError::InvalidNonce { expected: 1, found: 0 }));
+        // Nor can the sender skip ahead
+        assert_eq!(mempool.insert(payment(&keypair, 10, 1, 2), &blockchain), Err(BlockchainError::InvalidNonce { expected: 1, found: 2 }));
+        assert_eq!(mempool.select_batch(10), vec![first]);
+        assert_eq!(mempool.next_nonce(&keypair.public().into_peer_id().to_string(), &blockchain), 1);
+    }
+
+    #[test]
+    fn lowest_priority_is_evicted_when_full() {
+        let (a, b, c, d) = (Keypair::generate_secp256k1(), Keypair::generate_secp256k1(), Keypair::generate_secp256k1(), Keypair::generate_secp256k1());
+        let blockchain = funded_chain(&[&a, &b, &c, &d], 100);
+        assert_eq!(Mempool::default().max_size, MAX_MEMPOOL_SIZE);
+        let mut mempool = Mempool::new(3);
+        let (a0, a1, b0) = (payment(&a, 10, 1, 0), payment(&a, 10, 9, 1), payment(&b, 10, 5, 0));
+        for transaction in [a0.clone(), a1.clone(), b0.clone()].iter() {
+            assert_eq!(mempool.insert(transaction.clone(), &blockchain), Ok(true));
+        }
+
+        // a0 has the lowest fee but a1 depends on it, so b0 goes instead
+        let c0 = payment(&c, 10, 6, 0);
+        assert_eq!(mempool.insert(c0.clone(), &blockchain), Ok(true));
+        assert_eq!(mempool.len(), 3);
+        assert!(!mempool.contains(&b0.hash()));
+        assert!(mempool.contains(&a0.hash()) && mempool.contains(&a1.hash()) && mempool.contains(&c0.hash()));
+
+        // Nothing can go for one with the same fee as c0 that arrived later
+        assert_eq!(mempool.insert(payment(&d, 10, 6, 0), &blockchain), Err(BlockchainError::MempoolFull));
+        assert_eq!(mempool.len(), 3);
+    }
+
+    #[test]
+    fn prune_drops_mined_and_invalidated_transactions() {
+        let (a, b) = (Keypair::generate_secp256k1(), Keypair::generate_secp256k1());
+        let mut blockchain = funded_chain(&[&a, &b], 100);
+        let mut mempool = Mempool::default();
+        let (a0, a1, b0, b1) = (payment(&a, 10, 1, 0), payment(&a, 10, 1, 1), payment(&b, 30, 1, 0), payment(&b, 60, 1, 1));
+        for transaction in [a0.clone(), a1.clone(), b0.clone(), b1.clone()].iter() {
+            assert_eq!(mempool.insert(transaction.clone(), &blockchain), Ok(true));
+        }
+
+        // A block with a0 and a different transaction using b's first nonce, which leaves b too little for b1
+        mine_with(&mut blockchain, &PeerId::random(), vec![a0.clone(), payment(&b, 50, 1, 0)]);
+        mempool.prune(&blockchain);
+        assert_eq!(mempool.select_batch(10), vec![a1]);
+    }
+
+    #[test]
+    fn batches_keep_each_senders_nonce_order() {
+        let (a, b) = (Keypair::generate_secp256k1(), Keypair::generate_secp256k1());
+        let blockchain = funded_chain(&[&a, &b], 100);
+        let mut mempool = Mempool::default();
+        let (a0, a1, b0) = (payment(&a, 10, 1, 0), payment(&a, 10, 9, 1), payment(&b, 10, 5, 0));
+        for transaction in [a0.clone(), a1.clone(), b0.clone()].iter() {
+            assert_eq!(mempool.insert(transaction.clone(), &blockchain), Ok(true));
+        }
+
+        // a1 pays the most but can't go before a0
+        assert_eq!(mempool.select_batch(10), vec![b0.clone(), a0.clone(), a1]);
+        assert_eq!(mempool.select_batch(2), vec![b0, a0]);
+        assert!(blockchain.check_transactions(&mempool.select_batch(10)).is_ok());
+    }
+}
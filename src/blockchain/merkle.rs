@@ -0,0 +1,149 @@
+// Local imports
+use crate::blockchain::HASH_SIZE;
+// External imports
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+// Prefixes so a leaf can never be passed off as an inner node (or the other way around)
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+// One level of a proof, the hash to combine with and which side it goes on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MerkleStep {
+    pub hash: [u8; HASH_SIZE],
+    pub is_left: bool,
+}
+
+// Proof that a transaction is included under a block's merkle root
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MerkleProof {
+    steps: Vec<MerkleStep>,
+}
+
+fn hash_leaf(leaf: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf);
+
+    hasher.finalize().into()
+}
+
+fn hash_nodes(left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+
+    hasher.finalize().into()
+}
+
+// Hash each pair of nodes together to get the level above (an odd node out is carried up as is)
+fn next_level(level: &[[u8; HASH_SIZE]]) -> Vec<[u8; HASH_SIZE]> {
+    level.chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_nodes(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+// Root of the tree built from the given leaves (transaction hashes), all zeros if there are none
+pub fn merkle_root(leaves: &[[u8; HASH_SIZE]]) -> [u8; HASH_SIZE] {
+    if leaves.is_empty() {
+        return [0u8; HASH_SIZE];
+    }
+
+    let mut level: Vec<[u8; HASH_SIZE]> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level[0]
+}
+
+impl MerkleProof {
+    // Build the proof for the leaf at index, None if the index is out of range
+    pub fn generate(leaves: &[[u8; HASH_SIZE]], mut index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+
+        let mut steps = Vec::new();
+        let mut level: Vec<[u8; HASH_SIZE]> = leaves.iter().map(hash_leaf).collect();
+        while level.len() > 1 {
+            let sibling = index ^ 1;
+            // The last node on an odd level has no sibling and moves up unchanged
+            if sibling < level.len() {
+                steps.push(MerkleStep { hash: level[sibling], is_left: sibling < index });
+            }
+            level = next_level(&level);
+            index /= 2;
+        }
+
+        Some(Self { steps })
+    }
+
+    // Check that the leaf hashes up to the given root
+    pub fn verify(&self, leaf: &[u8; HASH_SIZE], root: &[u8; HASH_SIZE]) -> bool {
+        let computed = self.steps.iter().fold(hash_leaf(leaf), |hash, step| {
+            if step.is_left {
+                hash_nodes(&step.hash, &hash)
+            } else {
+                hash_nodes(&hash, &step.hash)
+            }
+        });
+
+        computed == *root
+    }
+
+    pub fn steps(&self) -> &[MerkleStep] {
+        self.steps.as_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<[u8; HASH_SIZE]> {
+        (0..count).map(|i| Sha256::digest(&i.to_le_bytes()).into()).collect()
+    }
+
+    #[test]
+    fn proofs_verify_for_every_leaf() {
+        // Odd sizes carry a node up without a sibling at one or more levels
+        for count in 1..=17 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::generate(&leaves, index).unwrap();
+                assert!(proof.verify(leaf, &root), "leaf {} of {}", index, count);
+            }
+            assert_eq!(MerkleProof::generate(&leaves, count), None);
+        }
+    }
+
+    #[test]
+    fn proofs_reject_the_wrong_leaf() {
+        let leaves = leaves(7);
+        let root = merkle_root(&leaves);
+        for index in 0..leaves.len() {
+            let proof = MerkleProof::generate(&leaves, index).unwrap();
+            for (other, leaf) in leaves.iter().enumerate().filter(|(other, _)| *other != index) {
+                assert!(!proof.verify(leaf, &root), "proof for {} accepted leaf {}", index, other);
+            }
+            assert!(!proof.verify(&[0u8; HASH_SIZE], &root));
+        }
+    }
+
+    #[test]
+    fn leaves_and_nodes_hash_differently() {
+        // A single leaf's root is its leaf hash, not the leaf itself
+        let leaves = leaves(1);
+        assert_eq!(merkle_root(&leaves), hash_leaf(&leaves[0]));
+        assert_ne!(merkle_root(&leaves), leaves[0]);
+        assert_eq!(merkle_root(&[]), [0u8; HASH_SIZE]);
+    }
+}
@@ -0,0 +1,174 @@
+// Local imports
+use crate::blockchain::Block;
+// Std imports
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Hashes each thread tries between checking whether it should stop
+const HASHES_PER_CHECK: u64 = 1024;
+
+// Searches for a nonce that makes a block meet its target, split across a pool of threads
+// Works on its own copy of the block so nothing else has to be locked while it runs
+#[derive(Debug)]
+pub struct Miner {
+    threads: usize,
+    // Set when the search should stop (a nonce was found or the block went stale)
+    stopped: Arc<AtomicBool>,
+    hashes: Arc<AtomicU64>,
+    started: Mutex<Option<Instant>>,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            stopped: Arc::new(AtomicBool::new(false)),
+            hashes: Arc::new(AtomicU64::new(0)),
+            started: Mutex::new(None),
+        }
+    }
+
+    // Mine the block, blocking until a nonce is found or the miner is cancelled (None if cancelled or the bits are invalid)
+    // Each miner runs one search, make a new one for the next block
+    pub fn mine(&self, block: &Block) -> Option<Block> {
+        let target = block.target()?;
+        *self.started.lock().unwrap() = Some(Instant::now());
+
+        let (found_sender, found_receiver) = channel();
+        // Give each thread its own slice of the nonce space so they never repeat each other's work
+        let range_size = u64::MAX / self.threads as u64;
+        let workers: Vec<_> = (0..self.threads as u64)
+            .map(|i| {
+                let mut block = block.clone();
+                // Everything but the nonce is only hashed once
+                let midstate = block.midstate();
+                let stopped = self.stopped.clone();
+                let hashes = self.hashes.clone();
+                let found_sender = found_sender.clone();
+                thread::spawn(move || {
+                    let mut nonce = i * range_size;
+                    let end = nonce + range_size;
+                    while !stopped.load(Ordering::Relaxed) && nonce < end {
+                        let batch_end = end.min(nonce + HASHES_PER_CHECK);
+                        let batch_start = nonce;
+                        while nonce < batch_end {
+                            if target.is_met_by(&Block::hash_with_nonce(&midstate, nonce)) {
+                                block.set_nonce(nonce);
+                                stopped.store(true, Ordering::Relaxed);
+                                let _ = found_sender.send(block);
+                                hashes.fetch_add(nonce - batch_start + 1, Ordering::Relaxed);
+                                return;
+                            }
+                            nonce += 1;
+                        }
+                        hashes.fetch_add(batch_end - batch_start, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        drop(found_sender);
+        for worker in workers {
+            worker.join().expect("Mining thread panicked");
+        }
+
+        found_receiver.try_recv().ok()
+    }
+
+    // Stop the search (from any thread), mine returns None soon after
+    pub fn cancel(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    // Hashes tried so far
+    pub fn hashes(&self) -> u64 {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    // Hashes per second since the search started
+    pub fn hashrate(&self) -> f64 {
+        match *self.started.lock().unwrap() {
+            Some(started) => self.hashes() as f64 / started.elapsed().as_secs_f64().max(f64::EPSILON),
+            None => 0.0,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+}
+
+// Single threaded hashes per second for the block, rehashing the whole header every time vs cloning the midstate
+pub fn benchmark(block: &Block, duration: Duration) -> (f64, f64) {
+    let mut block = block.clone();
+    let full_rate = hashes_per_second(duration, |nonce| {
+        block.set_nonce(nonce);
+    });
+    let midstate = block.midstate();
+    let midstate_rate = hashes_per_second(duration, |nonce| {
+        Block::hash_with_nonce(&midstate, nonce);
+    });
+
+    (full_rate, midstate_rate)
+}
+
+fn hashes_per_second<F: FnMut(u64)>(duration: Duration, mut hash: F) -> f64 {
+    let started = Instant::now();
+    let mut nonce = 0;
+    while started.elapsed() < duration {
+        for _ in 0..HASHES_PER_CHECK {
+            hash(nonce);
+            nonce += 1;
+        }
+    }
+
+    nonce as f64 / started.elapsed().as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Target, Transaction, HASH_SIZE};
+    use libp2p::PeerId;
+    use std::sync::mpsc::RecvTimeoutError;
+
+    fn block(target: Target) -> Block {
+        Block::new(vec![Transaction::coinbase(PeerId::random(), 50, 1)], 1, Arc::new([0u8; HASH_SIZE]), target.to_compact())
+    }
+
+    #[test]
+    fn found_nonces_meet_the_target() {
+        let block = block(Target::from_leading_zero_bits(12));
+        let miner = Miner::new(4);
+        let mined = miner.mine(&block).unwrap();
+
+        assert!(mined.has_valid_hash());
+        assert!(mined.meets_target());
+        // Only the nonce changed
+        let mut unmined = mined.clone();
+        unmined.set_nonce(0);
+        assert_eq!(unmined, block);
+        assert!(miner.hashes() > 0);
+    }
+
+    #[test]
+    fn cancelling_stops_every_thread() {
+        // Nothing is going to meet this target, so only cancelling ends the search
+        let block = block(Target::from_leading_zero_bits(200));
+        let miner = Arc::new(Miner::new(4));
+        let (done_sender, done_receiver) = channel();
+        let mining = miner.clone();
+        thread::spawn(move || done_sender.send(mining.mine(&block)).unwrap());
+
+        assert_eq!(done_receiver.recv_timeout(Duration::from_millis(100)).unwrap_err(), RecvTimeoutError::Timeout);
+        miner.cancel();
+        // mine only returns once it has joined all of its threads
+        assert_eq!(done_receiver.recv_timeout(Duration::from_secs(10)).unwrap(), None);
+        let hashes = miner.hashes();
+        assert!(hashes > 0);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(miner.hashes(), hashes);
+    }
+}
@@ -1,8 +1,26 @@
 
 mod block;
 mod blockchain;
+mod encoding;
+mod error;
+mod genesis;
+mod mempool;
+mod merkle;
+mod miner;
+mod storage;
+mod store;
+mod target;
 mod transaction;
 
-pub use block::{Block, GENESIS_BLOCK};
-pub use blockchain::Blockchain;
-pub use transaction::Transaction;
+pub use block::{Block, BlockHeader, HASH_SIZE};
+pub use blockchain::{Blockchain, ChainUpdate, InvalidBlock, MAX_BLOCK_TRANSACTIONS};
+pub use encoding::{put_peer_id, put_varint, Decode, DecodeError, Encode, Reader};
+pub use error::BlockchainError;
+pub use genesis::Genesis;
+pub use mempool::Mempool;
+pub use merkle::{MerkleProof, MerkleStep};
+pub use miner::{benchmark, Miner};
+pub use store::BlockStore;
+pub use storage::{Account, BlockEntry, DiskStorage, MemoryStorage, StateUpdate, Storage};
+pub use target::Target;
+pub use transaction::{CurrencyType, Transaction};
@@ -0,0 +1,199 @@
+// Local imports
+use crate::blockchain::storage::{Account, BlockEntry, BlockHash, StateUpdate, Storage};
+use crate::blockchain::{Decode, Encode, HASH_SIZE};
+// Std imports
+use std::convert::TryInto;
+use std::path::Path;
+// External imports
+use sled::Transactional;
+use sled::transaction::TransactionResult;
+
+// Accounts are stored as <balance: u64 LE><nonce: u64 LE>
+const ACCOUNT_SIZE: usize = 16;
+
+// Keeps everything in an embedded database, so lookups don't need the whole chain in memory and a restart picks up where it left off
+#[derive(Debug)]
+pub struct DiskStorage {
+    db: sled::Db,
+    // Block hash -> block entry (binary encoded)
+    blocks: sled::Tree,
+    // Parent hash followed by child hash -> nothing (scanned by parent hash to find children)
+    children: sled::Tree,
+    // Height (big endian so the keys sort by height) -> block hash
+    heights: sled::Tree,
+    // Peer id -> account
+    accounts: sled::Tree,
+}
+
+fn encode_account(account: &Account) -> [u8; ACCOUNT_SIZE] {
+    let mut bytes = [0u8; ACCOUNT_SIZE];
+    bytes[..8].copy_from_slice(&account.balance.to_le_bytes());
+    bytes[8..].copy_from_slice(&account.nonce.to_le_bytes());
+
+    bytes
+}
+
+fn decode_account(bytes: &[u8]) -> Account {
+    Account {
+        balance: u64::from_le_bytes(bytes[..8].try_into().expect("Corrupt account in storage")),
+        nonce: u64::from_le_bytes(bytes[8..ACCOUNT_SIZE].try_into().expect("Corrupt account in storage")),
+    }
+}
+
+fn decode_hash(bytes: &[u8]) -> BlockHash {
+    bytes.try_into().expect("Corrupt block hash in storage")
+}
+
+impl DiskStorage {
+    pub fn open(path: &Path) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+
+        Ok(Self {
+            blocks: db.open_tree("blocks")?,
+            children: db.open_tree("children")?,
+            heights: db.open_tree("heights")?,
+            accounts: db.open_tree("accounts")?,
+            db,
+        })
+    }
+}
+
+impl Storage for DiskStorage {
+    fn block(&self, hash: &BlockHash) -> Option<BlockEntry> {
+        self.blocks.get(hash).expect("Failed to read block from storage")
+            .map(|bytes| BlockEntry::decode(&bytes).expect("Corrupt block in storage"))
+    }
+
+    fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.blocks.contains_key(hash).expect("Failed to read block from storage")
+    }
+
+    fn insert_block(&mut self, entry: BlockEntry) {
+        let hash = *entry.block.hash();
+        let mut child_key = entry.block.previous_hash().to_vec();
+        child_key.extend_from_slice(&hash);
+        self.blocks.insert(&hash, entry.encode()).expect("Failed to write block to storage");
+        self.children.insert(child_key, &[]).expect("Failed to write block to storage");
+    }
+
+    fn remove_block(&mut self, hash: &BlockHash) {
+        if let Some(entry) = self.block(hash) {
+            let mut child_key = entry.block.previous_hash().to_vec();
+            child_key.extend_from_slice(hash);
+            self.children.remove(child_key).expect("Failed to remove block from storage");
+            self.blocks.remove(hash).expect("Failed to remove block from storage");
+        }
+    }
+
+    fn children(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        self.children.scan_prefix(hash)
+            .map(|item| decode_hash(&item.expect("Failed to read block from storage").0[HASH_SIZE..]))
+            .collect()
+    }
+
+    fn main_chain_hash(&self, height: u64) -> Option<BlockHash> {
+        self.heights.get(height.to_be_bytes()).expect("Failed to read main chain from storage")
+            .map(|bytes| decode_hash(&bytes))
+    }
+
+    fn main_chain_length(&self) -> u64 {
+        self.heights.last().expect("Failed to read main chain from storage")
+            .map_or(0, |(key, _)| u64::from_be_bytes(key.as_ref().try_into().expect("Corrupt height in storage")) + 1)
+    }
+
+    fn account(&self, peer_id: &str) -> Account {
+        self.accounts.get(peer_id.as_bytes()).expect("Failed to read account from storage")
+            .map_or_else(Account::default, |bytes| decode_account(&bytes))
+    }
+
+    fn accounts(&self) -> Vec<(String, Account)> {
+        self.accounts.iter()
+            .map(|item| {
+                let (key, value) = item.expect("Failed to read account from storage");
+                (String::from_utf8_lossy(&key).into_owned(), decode_account(&value))
+            })
+            .collect()
+    }
+
+    // The main chain and accounts are written in one transaction so a crash can't leave them out of step
+    fn commit(&mut self, update: StateUpdate) {
+        let length = self.main_chain_length();
+        let result: TransactionResult<()> = (&self.heights, &self.accounts).transaction(|(heights, accounts)| {
+            let mut next_height = length;
+            if let Some(truncate_to) = update.truncate_to {
+                for height in truncate_to..length {
+                    heights.remove(&height.to_be_bytes())?;
+                }
+                next_height = truncate_to;
+            }
+            for hash in update.connected.iter() {
+                heights.insert(&next_height.to_be_bytes(), &hash[..])?;
+                next_height += 1;
+            }
+            for (peer_id, account) in update.accounts.iter() {
+                // Accounts that went back to nothing (from reverting blocks) don't need an entry
+                if *account == Account::default() {
+                    accounts.remove(peer_id.as_bytes())?;
+                } else {
+                    accounts.insert(peer_id.as_bytes(), &encode_account(account)[..])?;
+                }
+            }
+
+            Ok(())
+        });
+        result.expect("Failed to write chain state to storage");
+        self.db.flush().expect("Failed to flush storage");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Blockchain, BlockchainError, Genesis, Target};
+    use crate::blockchain::blockchain::tests::mine_next;
+    use libp2p::PeerId;
+
+    // sled's flusher thread can hold on to the database's lock for a moment after the last handle is dropped
+    fn reopen(path: &Path) -> DiskStorage {
+        for _ in 0..100 {
+            if let Ok(storage) = DiskStorage::open(path) {
+                return storage;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        DiskStorage::open(path).unwrap()
+    }
+
+    #[test]
+    fn reopened_chain_is_validated() {
+        let path = std::env::temp_dir().join(format!("disk-storage-test-{}", std::process::id()));
+        let target = Target::from_leading_zero_bits(4);
+        let miner = PeerId::random();
+        {
+            let storage = DiskStorage::open(&path).unwrap();
+            let mut blockchain = Blockchain::with_storage(Genesis::default(), target, Box::new(storage)).unwrap();
+            for _ in 0..3 {
+                mine_next(&mut blockchain, &miner);
+            }
+        }
+
+        let blockchain = Blockchain::with_storage(Genesis::default(), target, Box::new(reopen(&path))).unwrap();
+        assert_eq!(blockchain.height(), 3);
+        assert!(blockchain.validate().is_ok());
+        drop(blockchain);
+
+        // Hand ourselves some money behind the chain's back
+        let mut storage = reopen(&path);
+        let mut update = StateUpdate::default();
+        update.accounts.insert(miner.to_string(), Account { balance: 1_000_000, nonce: 0 });
+        storage.commit(update);
+        drop(storage);
+
+        let blockchain = Blockchain::with_storage(Genesis::default(), target, Box::new(reopen(&path))).unwrap();
+        assert_eq!(blockchain.validate().unwrap_err().error, BlockchainError::BalanceMismatch);
+
+        drop(blockchain);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+// Local imports
+use crate::blockchain::storage::{Account, BlockEntry, BlockHash, StateUpdate, Storage};
+// Std imports
+use std::collections::HashMap;
+
+// Keeps everything in memory, so it's lost when the node stops (the block store can replay it)
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    blocks: HashMap<BlockHash, BlockEntry>,
+    children: HashMap<BlockHash, Vec<BlockHash>>,
+    main_chain: Vec<BlockHash>,
+    accounts: HashMap<String, Account>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn block(&self, hash: &BlockHash) -> Option<BlockEntry> {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn contains_block(&self, hash: &BlockHash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    fn insert_block(&mut self, entry: BlockEntry) {
+        let hash = *entry.block.hash();
+        self.children.entry(*entry.block.previous_hash()).or_default().push(hash);
+        self.blocks.insert(hash, entry);
+    }
+
+    fn remove_block(&mut self, hash: &BlockHash) {
+        if let Some(entry) = self.blocks.remove(hash) {
+            if let Some(siblings) = self.children.get_mut(&*entry.block.previous_hash()) {
+                siblings.retain(|sibling| sibling != hash);
+            }
+        }
+    }
+
+    fn children(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        self.children.get(hash).cloned().unwrap_or_default()
+    }
+
+    fn main_chain_hash(&self, height: u64) -> Option<BlockHash> {
+        self.main_chain.get(height as usize).copied()
+    }
+
+    fn main_chain_length(&self) -> u64 {
+        self.main_chain.len() as u64
+    }
+
+    fn account(&self, peer_id: &str) -> Account {
+        self.accounts.get(peer_id).copied().unwrap_or_default()
+    }
+
+    fn accounts(&self) -> Vec<(String, Account)> {
+        self.accounts.iter().map(|(peer_id, account)| (peer_id.clone(), *account)).collect()
+    }
+
+    fn commit(&mut self, update: StateUpdate) {
+        if let Some(length) = update.truncate_to {
+            self.main_chain.truncate(length as usize);
+        }
+        self.main_chain.extend(update.connected);
+        for (peer_id, account) in update.accounts {
+            // Accounts that went back to nothing (from reverting blocks) don't need an entry
+            if account == Account::default() {
+                self.accounts.remove(&peer_id);
+            } else {
+                self.accounts.insert(peer_id, account);
+            }
+        }
+    }
+}
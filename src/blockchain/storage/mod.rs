@@ -0,0 +1,84 @@
+// Local imports
+use crate::blockchain::{Block, CurrencyType, Decode, DecodeError, Encode, Reader, Target, HASH_SIZE};
+// Std imports
+use std::collections::HashMap;
+use std::fmt;
+// External imports
+use serde::{Serialize, Deserialize};
+
+mod disk;
+mod memory;
+
+pub use disk::DiskStorage;
+pub use memory::MemoryStorage;
+
+pub type BlockHash = [u8; HASH_SIZE];
+
+// What an account has as of the main chain's tip
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Account {
+    pub balance: CurrencyType,
+    // How many transactions the account has sent (the nonce its next transaction must use)
+    pub nonce: u64,
+}
+
+// A block we know about along with the target it had to meet and the total work of the chain ending in it
+// (the target is kept separately since the genesis block's header doesn't have one)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BlockEntry {
+    pub block: Block,
+    pub target: Target,
+    pub total_work: u128,
+}
+
+// The block's encoding followed by the target and total work
+impl Encode for BlockEntry {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        self.block.encode_to(bytes);
+        bytes.extend_from_slice(self.target.as_bytes());
+        bytes.extend_from_slice(&self.total_work.to_le_bytes());
+    }
+}
+
+impl Decode for BlockEntry {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Self {
+            block: Block::decode_from(reader)?,
+            target: Target::from_bytes(reader.hash()?),
+            total_work: reader.u128()?,
+        })
+    }
+}
+
+// Changes to the main chain and accounts from adding a block, which have to be written all at once
+#[derive(Debug, Clone, Default)]
+pub struct StateUpdate {
+    // Length to cut the main chain back to before connecting blocks (when switching branches)
+    pub truncate_to: Option<u64>,
+    // Hashes to add to the end of the main chain, oldest first
+    pub connected: Vec<BlockHash>,
+    // New state of every account that changed
+    pub accounts: HashMap<String, Account>,
+}
+
+// Where the blockchain keeps its blocks and account state, looked up by block hash, height and account
+pub trait Storage: fmt::Debug + Send + Sync {
+    // Every valid block we know about, including ones on competing branches
+    fn block(&self, hash: &BlockHash) -> Option<BlockEntry>;
+    fn contains_block(&self, hash: &BlockHash) -> bool;
+    fn insert_block(&mut self, entry: BlockEntry);
+    fn remove_block(&mut self, hash: &BlockHash);
+    // Hashes of the blocks built directly on top of a block
+    fn children(&self, hash: &BlockHash) -> Vec<BlockHash>;
+
+    // The main chain by height, from genesis to the block with the most total work
+    fn main_chain_hash(&self, height: u64) -> Option<BlockHash>;
+    fn main_chain_length(&self) -> u64;
+
+    // Accounts nobody has paid yet have nothing
+    fn account(&self, peer_id: &str) -> Account;
+    // Every account with a balance or nonce
+    fn accounts(&self) -> Vec<(String, Account)>;
+
+    fn commit(&mut self, update: StateUpdate);
+}
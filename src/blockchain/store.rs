@@ -0,0 +1,129 @@
+// Local imports
+use crate::blockchain::{Block, Decode, Encode, HASH_SIZE};
+// Std imports
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+// External imports
+use sha2::{Sha256, Digest};
+
+// File in the data directory the blocks are appended to
+const BLOCKS_FILE: &'static str = "blocks.dat";
+// Start of every blocks file, followed by the hash of the genesis block the blocks build on
+const MAGIC: &'static [u8; 8] = b"P2PBLKS1";
+const FILE_HEADER_SIZE: usize = MAGIC.len() + HASH_SIZE;
+// Each record is <payload length: u32 LE><first bytes of the payload's sha256><payload>
+const CHECKSUM_SIZE: usize = 4;
+const RECORD_HEADER_SIZE: usize = 4 + CHECKSUM_SIZE;
+
+// Append only log of every block we accepted (main chain or not) in the order we accepted them,
+// so replaying it from the start always sees a block's parent before the block
+#[derive(Debug)]
+pub struct BlockStore {
+    path: PathBuf,
+    file: File,
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = Sha256::digest(payload);
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&hash[..CHECKSUM_SIZE]);
+
+    checksum
+}
+
+impl BlockStore {
+    // Open (or create) the store in a data directory for the chain starting at genesis_hash
+    // A record cut off by a crash part way through a write is dropped
+    pub fn open(directory: &Path, genesis_hash: &[u8; HASH_SIZE]) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(BLOCKS_FILE);
+        if !path.exists() {
+            Self::create(&path, genesis_hash)?;
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        // Check the header before reading any further, so something that isn't a block store never gets read in full
+        let mut header = [0u8; FILE_HEADER_SIZE];
+        let is_block_store = match file.read_exact(&mut header) {
+            Ok(()) => &header[..MAGIC.len()] == MAGIC,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(error) => return Err(error),
+        };
+        if !is_block_store {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a block store", path.display())));
+        }
+        if &header[MAGIC.len()..] != genesis_hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} holds blocks from a different genesis", path.display())));
+        }
+        let mut contents = header.to_vec();
+        file.read_to_end(&mut contents)?;
+
+        // Cut off anything after the last complete record so new records don't land after garbage
+        let valid_length = Self::records(&contents).last().map_or(FILE_HEADER_SIZE, |(end, _)| *end);
+        if valid_length < contents.len() {
+            eprintln!("Dropping {} bytes of incomplete records from {}", contents.len() - valid_length, path.display());
+            file.set_len(valid_length as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self { path, file })
+    }
+
+    // Write the file header to a temporary file and move it into place, so a crash never leaves a half written header
+    fn create(path: &Path, genesis_hash: &[u8; HASH_SIZE]) -> io::Result<()> {
+        let temporary_path = path.with_extension("tmp");
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(genesis_hash)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)
+    }
+
+    // Every complete record after the file header, along with the offset it ends at
+    fn records(contents: &[u8]) -> Vec<(usize, &[u8])> {
+        let mut records = Vec::new();
+        let mut offset = FILE_HEADER_SIZE;
+        while contents.len() - offset >= RECORD_HEADER_SIZE {
+            let length = u32::from_le_bytes(contents[offset..offset + 4].try_into().unwrap()) as usize;
+            let start = offset + RECORD_HEADER_SIZE;
+            if contents.len() - start < length {
+                break;
+            }
+            let payload = &contents[start..start + length];
+            if checksum(payload) != contents[offset + 4..start] {
+                break;
+            }
+            offset = start + length;
+            records.push((offset, payload));
+        }
+
+        records
+    }
+
+    // Add a block to the end of the store, only returning once it's on disk
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let payload = block.encode();
+        // One write for the whole record so a crash can only cut off the end of it
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    // Every stored block in the order they were added
+    pub fn read_blocks(&self) -> io::Result<Vec<Block>> {
+        let contents = fs::read(&self.path)?;
+        Self::records(&contents).into_iter()
+            .map(|(_, payload)| Block::decode(payload).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)))
+            .collect()
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
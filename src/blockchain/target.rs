@@ -0,0 +1,302 @@
+// Local imports
+use crate::blockchain::HASH_SIZE;
+// External imports
+use serde::{Serialize, Deserialize};
+
+// 256 bit unsigned number stored as big endian 64 bit limbs (just enough math for targets and work)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct U256([u64; 4]);
+
+impl U256 {
+    const ZERO: U256 = U256([0; 4]);
+    const MAX: U256 = U256([u64::MAX; 4]);
+
+    fn from_bytes(bytes: &[u8; HASH_SIZE]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut limb_bytes = [0u8; 8];
+            limb_bytes.copy_from_slice(&bytes[i * 8..(i + 1) * 8]);
+            *limb = u64::from_be_bytes(limb_bytes);
+        }
+
+        U256(limbs)
+    }
+
+    fn to_bytes(&self) -> [u8; HASH_SIZE] {
+        let mut bytes = [0u8; HASH_SIZE];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&limb.to_be_bytes());
+        }
+
+        bytes
+    }
+
+    // Multiply then divide by small numbers, None if the result doesn't fit in 256 bits
+    fn mul_div(&self, numerator: u64, denominator: u64) -> Option<Self> {
+        // Multiply into 5 limbs so nothing is lost before dividing
+        let mut product = [0u64; 5];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let value = self.0[i] as u128 * numerator as u128 + carry;
+            product[i + 1] = value as u64;
+            carry = value >> 64;
+        }
+        product[0] = carry as u64;
+
+        // Long division one limb at a time
+        let mut quotient = [0u64; 5];
+        let mut remainder = 0u128;
+        for i in 0..5 {
+            let value = (remainder << 64) | product[i] as u128;
+            quotient[i] = (value / denominator as u128) as u64;
+            remainder = value % denominator as u128;
+        }
+
+        if quotient[0] != 0 {
+            return None;
+        }
+
+        Some(U256([quotient[1], quotient[2], quotient[3], quotient[4]]))
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        (self.0[3 - index / 64] >> (index % 64)) & 1 == 1
+    }
+
+    fn shl1(&self) -> Self {
+        let mut limbs = [0u64; 4];
+        for i in 0..4 {
+            limbs[i] = self.0[i] << 1;
+            if i < 3 {
+                limbs[i] |= self.0[i + 1] >> 63;
+            }
+        }
+
+        U256(limbs)
+    }
+
+    fn wrapping_sub(&self, other: &Self) -> Self {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (value, borrow_a) = self.0[i].overflowing_sub(other.0[i]);
+            let (value, borrow_b) = value.overflowing_sub(borrow as u64);
+            limbs[i] = value;
+            borrow = borrow_a || borrow_b;
+        }
+
+        U256(limbs)
+    }
+
+    fn checked_add_one(&self) -> Option<Self> {
+        let mut limbs = self.0;
+        for i in (0..4).rev() {
+            let (value, overflow) = limbs[i].overflowing_add(1);
+            limbs[i] = value;
+            if !overflow {
+                return Some(U256(limbs));
+            }
+        }
+
+        None
+    }
+
+    // Shift and subtract division (only used when comparing chains, so speed doesn't matter)
+    fn div(&self, divisor: &Self) -> Self {
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for index in (0..256).rev() {
+            let carried_out = remainder.bit(255);
+            remainder = remainder.shl1();
+            if self.bit(index) {
+                remainder.0[3] |= 1;
+            }
+            if carried_out || remainder >= *divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.0[3 - index / 64] |= 1 << (index % 64);
+            }
+        }
+
+        quotient
+    }
+}
+
+// A block's hash, read as a big endian 256 bit number, has to be at or below its target (a lower target is harder)
+// Byte arrays compare lexicographically, which is the same as comparing them as big endian numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct Target([u8; HASH_SIZE]);
+
+impl Target {
+    // The easiest possible target, every hash meets it
+    pub const MAX: Target = Target([0xff; HASH_SIZE]);
+
+    pub fn from_bytes(bytes: [u8; HASH_SIZE]) -> Self {
+        Target(bytes)
+    }
+
+    // Target that needs the first zero_bytes bytes of the hash to be zero (what the difficulty used to mean)
+    pub fn from_leading_zero_bytes(zero_bytes: u8) -> Self {
+        Self::from_leading_zero_bits(zero_bytes as u32 * 8)
+    }
+
+    // Target that needs the first zero_bits bits of the hash to be zero, so each step only doubles the work
+    pub fn from_leading_zero_bits(zero_bits: u32) -> Self {
+        let mut bytes = [0xff; HASH_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let bits_in_byte = zero_bits.saturating_sub(i as u32 * 8).min(8);
+            *byte = 0xff_u16.checked_shr(bits_in_byte).unwrap_or(0) as u8;
+        }
+
+        Target(bytes)
+    }
+
+    // Decode the compact "bits" form stored in block headers: 1 byte of length followed by the top 3 bytes of the number
+    // None if the encoding is negative or doesn't fit in 256 bits
+    pub fn from_compact(bits: u32) -> Option<Self> {
+        let size = (bits >> 24) as usize;
+        let mantissa = bits & 0x007f_ffff;
+        if bits & 0x0080_0000 != 0 && mantissa != 0 {
+            return None;
+        }
+
+        let mut bytes = [0u8; HASH_SIZE];
+        let mantissa_bytes = [(mantissa >> 16) as u8, (mantissa >> 8) as u8, mantissa as u8];
+        for (k, &byte) in mantissa_bytes.iter().enumerate() {
+            // Where this byte of the mantissa lands in the big endian number (bytes past the end are dropped)
+            let position = HASH_SIZE as isize - size as isize + k as isize;
+            if position < 0 {
+                if byte != 0 {
+                    return None;
+                }
+            } else if (position as usize) < HASH_SIZE {
+                bytes[position as usize] = byte;
+            }
+        }
+
+        Some(Target(bytes))
+    }
+
+    // Encode into the compact "bits" form (anything past the top 3 bytes is rounded down to zero)
+    pub fn to_compact(&self) -> u32 {
+        let first_non_zero = match self.0.iter().position(|&byte| byte != 0) {
+            Some(position) => position,
+            None => return 0,
+        };
+        let mut size = (HASH_SIZE - first_non_zero) as u32;
+        let byte_at = |position: usize| self.0.get(position).copied().unwrap_or(0) as u32;
+        let mut mantissa = (byte_at(first_non_zero) << 16) | (byte_at(first_non_zero + 1) << 8) | byte_at(first_non_zero + 2);
+        // The top bit of the mantissa is a sign bit, so shift over a byte if it would be set
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        (size << 24) | mantissa
+    }
+
+    // The target as it would be read back from a block header
+    pub fn rounded(&self) -> Self {
+        Self::from_compact(self.to_compact()).unwrap_or(*self)
+    }
+
+    pub fn is_met_by(&self, hash: &[u8; HASH_SIZE]) -> bool {
+        hash <= &self.0
+    }
+
+    // Target scaled by numerator / denominator (more time taken means an easier target), capped at MAX
+    pub fn scale(&self, numerator: u64, denominator: u64) -> Self {
+        U256::from_bytes(&self.0).mul_div(numerator, denominator.max(1))
+            .map(|scaled| Target(scaled.to_bytes()))
+            .unwrap_or(Target::MAX)
+    }
+
+    // Expected number of hashes needed to meet this target, 2^256 / (target + 1) (saturates at u128::MAX)
+    pub fn work(&self) -> u128 {
+        let target = U256::from_bytes(&self.0);
+        let work = match target.checked_add_one() {
+            // (2^256 - 1 - target) / (target + 1) + 1 is the same as 2^256 / (target + 1) without needing 257 bits
+            Some(divisor) => U256::MAX.wrapping_sub(&target).div(&divisor).checked_add_one().unwrap_or(U256::MAX),
+            None => return 1,
+        };
+        if work.0[0] != 0 || work.0[1] != 0 {
+            return u128::MAX;
+        }
+
+        ((work.0[2] as u128) << 64) | work.0[3] as u128
+    }
+
+    pub fn as_bytes(&self) -> &[u8; HASH_SIZE] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZERO: Target = Target([0; HASH_SIZE]);
+
+    #[test]
+    fn compact_round_trips() {
+        for &bits in [0x1d00ffff, 0x1b0404cb, 0x207fffff, 0x2100ffff, 0x03123456, 0x02123400, 0x01120000, 0x02008000].iter() {
+            let target = Target::from_compact(bits).unwrap();
+            assert_eq!(target.to_compact(), bits, "{:#010x}", bits);
+        }
+        // A leading byte with its top bit set moves over a byte instead of reading as negative
+        let mut bytes = [0u8; HASH_SIZE];
+        bytes[HASH_SIZE - 1] = 0x80;
+        assert_eq!(Target(bytes).to_compact(), 0x02008000);
+    }
+
+    #[test]
+    fn compact_boundaries() {
+        // Negative
+        assert_eq!(Target::from_compact(0x04923456), None);
+        // Sign bit with nothing else is just zero
+        assert_eq!(Target::from_compact(0x00800000), Some(ZERO));
+        assert_eq!(Target::from_compact(0), Some(ZERO));
+        assert_eq!(ZERO.to_compact(), 0);
+        // Size 33 only fits if the byte that falls off the top is zero
+        assert_eq!(Target::from_compact(0x21010000), None);
+        let mut top = [0u8; HASH_SIZE];
+        top[0] = 0xff;
+        top[1] = 0xff;
+        assert_eq!(Target::from_compact(0x2100ffff), Some(Target(top)));
+        // MAX doesn't fit in 3 bytes of mantissa so it rounds down
+        assert_eq!(Target::MAX.to_compact(), 0x2100ffff);
+        assert_eq!(Target::MAX.rounded(), Target(top));
+        // Mantissa bytes past the end of the number are dropped
+        assert_eq!(Target::from_compact(0x01123456), Target::from_compact(0x01120000));
+    }
+
+    #[test]
+    fn work_matches_known_values() {
+        assert_eq!(Target::MAX.work(), 1);
+        assert_eq!(Target::from_leading_zero_bits(1).work(), 2);
+        // 2^256 / 2^240
+        assert_eq!(Target::from_leading_zero_bits(16).work(), 1 << 16);
+        assert_eq!(Target::from_leading_zero_bits(100).work(), 1 << 100);
+        // Bitcoin's difficulty 1 target
+        assert_eq!(Target::from_compact(0x1d00ffff).unwrap().work(), 0x1_0001_0001);
+        // More than fits in a u128
+        assert_eq!(Target::from_leading_zero_bits(200).work(), u128::MAX);
+        assert_eq!(ZERO.work(), u128::MAX);
+    }
+
+    #[test]
+    fn scale_is_exact_and_saturates() {
+        let target = Target::from_leading_zero_bits(16);
+        assert_eq!(target.scale(1, 4), Target::from_leading_zero_bits(18));
+        assert_eq!(target.scale(3, 3), target);
+        // Scaling back up can't bring back the bits the division dropped
+        let mut scaled_up = Target::from_leading_zero_bits(16).0;
+        scaled_up[HASH_SIZE - 1] = 0xfc;
+        assert_eq!(Target::from_leading_zero_bits(18).scale(4, 1), Target(scaled_up));
+        // Anything past 256 bits is capped at MAX
+        assert_eq!(Target::from_leading_zero_bits(1).scale(4, 1), Target::MAX);
+        assert_eq!(Target::MAX.scale(2, 1), Target::MAX);
+        assert_eq!(Target::MAX.scale(1, 1), Target::MAX);
+        // A zero denominator is treated as one
+        assert_eq!(target.scale(1, 0), target);
+    }
+}
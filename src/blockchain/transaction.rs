@@ -1,27 +1,91 @@
-
+use crate::blockchain::HASH_SIZE;
+use crate::blockchain::encoding::{put_bytes, put_peer_id, put_varint, Decode, DecodeError, Encode, Reader};
 use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
 use libp2p::PeerId;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::identity::error::SigningError;
+use std::str::FromStr;
 
 type PeerIdString = String;
 
 pub type CurrencyType = u64;
 
+// Multihash code used by peer ids that embed the public key instead of hashing it
+const IDENTITY_MULTIHASH_CODE: u8 = 0x00;
+
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     pub sender: PeerIdString,
     pub receiver: PeerIdString,
-    pub amount: CurrencyType
+    pub amount: CurrencyType,
+    // Paid by the sender on top of the amount to whoever mines the transaction
+    #[serde(default)]
+    pub fee: CurrencyType,
+    // Position of this transaction in the sender's sequence (stops old transactions from being replayed)
+    pub nonce: u64,
+    // Signature over signing_bytes() made with the sender's keypair
+    #[serde(default)]
+    pub signature: Vec<u8>,
 }
 
 impl Transaction {
-    pub fn new(sender: PeerId, receiver: PeerId, amount: CurrencyType) -> Self {
+    pub fn new(sender: PeerId, receiver: PeerId, amount: CurrencyType, fee: CurrencyType, nonce: u64) -> Self {
         Self {
             sender: sender.to_string(),
             receiver: receiver.to_string(),
-            amount
+            amount,
+            fee,
+            nonce,
+            signature: Vec::new(),
+        }
+    }
+    // The first transaction in every block, paying the block reward (new money plus fees) to the miner
+    // It has no sender and uses the block's index as its nonce so every coinbase has a different hash
+    pub fn coinbase(miner: PeerId, reward: CurrencyType, index: u64) -> Self {
+        Self {
+            sender: String::new(),
+            receiver: miner.to_string(),
+            amount: reward,
+            fee: 0,
+            nonce: index,
+            signature: Vec::new(),
         }
     }
+    pub fn is_coinbase(&self) -> bool {
+        self.sender.is_empty()
+    }
+    // Canonical bytes that get signed (the encoding of every field except the signature)
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_peer_id(&mut bytes, &self.sender);
+        put_peer_id(&mut bytes, &self.receiver);
+        put_varint(&mut bytes, self.amount);
+        put_varint(&mut bytes, self.fee);
+        put_varint(&mut bytes, self.nonce);
+
+        bytes
+    }
+    // Unique id of the transaction, the hash of its encoding (covers the signature too)
+    pub fn hash(&self) -> [u8; HASH_SIZE] {
+        Sha256::digest(&self.encode()).into()
+    }
+    // Sign the transaction with the sender's keypair
+    pub fn sign(&mut self, keypair: &Keypair) -> Result<(), SigningError> {
+        self.signature = keypair.sign(&self.signing_bytes())?;
+
+        Ok(())
+    }
+    // Check the signature against the public key embedded in the sender's peer id
+    pub fn has_valid_signature(&self) -> bool {
+        let public_key = match PeerId::from_str(&self.sender).ok().and_then(|peer_id| public_key_from_peer_id(&peer_id)) {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+
+        public_key.verify(&self.signing_bytes(), &self.signature)
+    }
     pub fn sender(&self) -> PeerIdString {
         self.sender.clone()
     }
@@ -31,10 +95,108 @@ impl Transaction {
     pub fn amount(&self) -> CurrencyType {
         self.amount
     }
+    pub fn fee(&self) -> CurrencyType {
+        self.fee
+    }
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+    pub fn signature(&self) -> &[u8] {
+        self.signature.as_slice()
+    }
+}
+
+impl Encode for Transaction {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.signing_bytes());
+        put_bytes(bytes, &self.signature);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Self {
+            sender: reader.peer_id()?,
+            receiver: reader.peer_id()?,
+            amount: reader.varint()?,
+            fee: reader.varint()?,
+            nonce: reader.varint()?,
+            signature: reader.bytes()?.to_vec(),
+        })
+    }
+}
+
+// Recover the public key from a peer id (only possible for small keys like secp256k1 that aren't hashed)
+fn public_key_from_peer_id(peer_id: &PeerId) -> Option<PublicKey> {
+    // Peer ids are multihashes: <code><digest length><digest>, where the identity digest is the protobuf encoded key
+    let bytes = peer_id.as_bytes();
+    if bytes.len() < 2 || bytes[0] != IDENTITY_MULTIHASH_CODE || bytes[1] as usize != bytes.len() - 2 {
+        return None;
+    }
+    let public_key = PublicKey::from_protobuf_encoding(&bytes[2..]).ok()?;
+    // Make sure the key we decoded is actually the one this peer id was made from
+    if peer_id.is_public_key(&public_key) != Some(true) {
+        return None;
+    }
+
+    Some(public_key)
 }
 
-pub(in super) const NULL_TRANSACTION: Transaction = Transaction {
-    sender: String::new(),
-    receiver: String::new(),
-    amount: 0
-};
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_by(keypair: &Keypair, sender: PeerId) -> Transaction {
+        let mut transaction = Transaction::new(sender, PeerId::random(), 10, 1, 0);
+        transaction.sign(keypair).unwrap();
+
+        transaction
+    }
+
+    #[test]
+    fn signatures_verify_against_the_sender() {
+        let keypair = Keypair::generate_secp256k1();
+        let sender = keypair.public().into_peer_id();
+        assert_eq!(public_key_from_peer_id(&sender), Some(keypair.public()));
+        assert!(signed_by(&keypair, sender).has_valid_signature());
+    }
+
+    #[test]
+    fn unsigned_transactions_are_rejected() {
+        let sender = Keypair::generate_secp256k1().public().into_peer_id();
+        assert!(!Transaction::new(sender, PeerId::random(), 10, 1, 0).has_valid_signature());
+        assert!(!Transaction::coinbase(PeerId::random(), 50, 1).has_valid_signature());
+    }
+
+    #[test]
+    fn forged_signatures_are_rejected() {
+        let victim = Keypair::generate_secp256k1().public().into_peer_id();
+        // Signed by someone else in the victim's name
+        assert!(!signed_by(&Keypair::generate_secp256k1(), victim).has_valid_signature());
+
+        // Signed properly, then changed
+        let keypair = Keypair::generate_secp256k1();
+        let mut transaction = signed_by(&keypair, keypair.public().into_peer_id());
+        transaction.amount += 1;
+        assert!(!transaction.has_valid_signature());
+
+        // A signature that isn't one at all
+        let mut transaction = signed_by(&keypair, keypair.public().into_peer_id());
+        transaction.signature = vec![0u8; transaction.signature.len()];
+        assert!(!transaction.has_valid_signature());
+    }
+
+    #[test]
+    fn hashed_peer_ids_have_no_public_key() {
+        let keypair = Keypair::generate_secp256k1();
+        // The same key's peer id, but as a sha256 multihash of the key instead of the key itself
+        let mut bytes = vec![0x12, HASH_SIZE as u8];
+        bytes.extend_from_slice(&Sha256::digest(&keypair.public().into_protobuf_encoding()));
+        let hashed = PeerId::from_bytes(bytes).unwrap();
+        assert_eq!(public_key_from_peer_id(&hashed), None);
+        assert!(!signed_by(&keypair, hashed).has_valid_signature());
+
+        // Identity multihashes of something that isn't a key
+        assert_eq!(public_key_from_peer_id(&PeerId::random()), None);
+    }
+}
@@ -2,11 +2,15 @@
 mod swarm;
 mod peer_data;
 mod blockchain;
+mod message;
+mod sync;
 
 extern crate native_windows_gui as nwg;
 extern crate native_windows_derive as nwd;
 // Local imports
-use crate::swarm::{spawn_swarm, BLOCKCHAIN_TOPIC, IDENTIFY_TOPIC, dial_address};
+use crate::swarm::{spawn_swarm, topic, ANNOUNCE_TOPIC, BLOCKCHAIN_TOPIC, TRANSACTION_TOPIC, add_known_peer, bootstrap, dial_address, dial_discovered_peer, report_validation, NodeBehaviour, NodeEvent, Validation};
+use crate::message::{hex, MessageError, NetworkMessage};
+use crate::sync::{ChainSync, ChainTip, SyncRequest, SyncResponse, MAX_BLOCKS, MAX_HEADERS, MAX_LOCATOR_HASHES};
 use crate::peer_data::{get_keypair, get_known_peers, save_known_peer, PeerData, save_known_peers};
 use crate::blockchain::*;
 // Std imports
@@ -18,19 +22,23 @@ use std::{
 };
 // External imports
 use futures::prelude::*;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
 use async_std::{task, io};
-use libp2p::gossipsub::{GossipsubEvent, Topic, Gossipsub};
+use libp2p::gossipsub::GossipsubEvent;
+use libp2p::kad::{GetClosestPeersOk, KademliaEvent, QueryResult};
+use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage};
 use serde::{Serialize, Deserialize};
 use futures::StreamExt;
 use libp2p::Swarm;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::core::Multiaddr;
 use libp2p::PeerId;
+use libp2p::identity::Keypair;
 use lazy_static::*;
 
 use std::thread;
 use std::sync::mpsc::{Receiver, channel, Sender};
-use std::sync::{RwLock, Mutex};
+use std::sync::{Arc, RwLock, Mutex};
 
 // Gui imports
 use nwd::NwgUi;
@@ -41,14 +49,43 @@ use std::str::FromStr;
 
 const DEFAULT_PORT: u16 = 4000;
 const MAX_PEERS: usize = 10;
+// Leading zero bits the first blocks need before the target starts adjusting
+const INITIAL_DIFFICULTY_BITS: u32 = 8;
+// Threads to mine with unless MINING_THREADS says otherwise
+const DEFAULT_MINING_THREADS: usize = 4;
+// Fee paid to miners when sending from the GUI (or stdin without a fee)
+const DEFAULT_TRANSACTION_FEE: CurrencyType = 1;
+// How long the bench command hashes with each method
+const BENCHMARK_SECONDS: u64 = 3;
+// Where the network's starting allocations are read from unless GENESIS_FILE says otherwise
+const DEFAULT_GENESIS_FILE: &'static str = "./genesis.json";
+// How many bytes of the genesis hash name the network unless NETWORK_ID says otherwise
+const NETWORK_ID_BYTES: usize = 4;
+// Where blocks are saved between runs unless DATA_DIR says otherwise
+const DEFAULT_DATA_DIR: &'static str = "./data";
+// Directory inside the data directory the disk storage database lives in
+const STORAGE_DIR: &'static str = "db";
 
 // JEFF ADDED
 lazy_static! {
-    pub static ref BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new(1));
+    pub static ref GENESIS: Genesis = load_genesis();
 
-    pub static ref MY_PEER_ID: PeerId = PeerId::from_public_key(get_keypair().public());
+    // Part of every topic name, so nodes only hear from peers on the same network
+    pub static ref NETWORK_ID: String = env::var("NETWORK_ID").unwrap_or_else(|_| hex(&GENESIS.block().hash()[..NETWORK_ID_BYTES]));
 
-    pub static ref SWARM: Mutex<Swarm<Gossipsub>> = Mutex::new(spawn_swarm(get_keypair(), MY_PEER_ID.clone()));
+    // Always lock after BLOCKCHAIN when both are needed (None when disk storage keeps the blocks instead)
+    pub static ref BLOCK_STORE: Mutex<Option<BlockStore>> = Mutex::new(open_block_store());
+
+    pub static ref BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(load_blockchain());
+
+    // Always lock before BLOCKCHAIN when both are needed
+    pub static ref MEMPOOL: RwLock<Mempool> = RwLock::new(Mempool::default());
+
+    pub static ref MY_KEYPAIR: Keypair = get_keypair();
+
+    pub static ref MY_PEER_ID: PeerId = PeerId::from_public_key(MY_KEYPAIR.public());
+
+    pub static ref SWARM: Mutex<Swarm<NodeBehaviour>> = Mutex::new(spawn_swarm(MY_KEYPAIR.clone(), MY_PEER_ID.clone(), &NETWORK_ID));
 
     pub static ref MY_GUI_BAL: RwLock<u64> = RwLock::new(BLOCKCHAIN.read().unwrap().get_balance(MY_PEER_ID.to_string()));
 }
@@ -100,12 +137,15 @@ pub struct MessageBank {
     #[nwg_layout_item(layout: layout, col: 2, row: 1, col_span: 1)]
     balance: nwg::Label,
 
-    #[nwg_control(text:"5000")]
+    #[nwg_control(text:"0")]
     #[nwg_layout_item(layout: layout, col: 3, row: 1, col_span: 1)]
     curr_balance: nwg::Label,
 
     boxes: RefCell<Vec<nwg::CheckBox>>,
     handlers: RefCell<Vec<nwg::EventHandler>>,
+
+    // Hands our validated transactions to the network loop to be published and mined
+    local_transactions: Option<UnboundedSender<Transaction>>,
 }
 
 impl MessageBank {
@@ -212,14 +252,27 @@ impl MessageBank {
                 }
 
                 // JEFF ADDED
-                let transaction = Transaction::new(MY_PEER_ID.clone(), PeerId::from_str(&checks[n]).expect("Invalid PeerId"), sent_amount_int as u64);
-                // Add transaction to local blockchain
-                BLOCKCHAIN.write().unwrap().add_transaction(transaction);
-                let serialized_block = serde_json::to_string(BLOCKCHAIN.read().unwrap().latest_block()).expect("Failed to serialize block");
-                // Send to peers
-                SWARM.lock().unwrap().publish(&Topic::new(BLOCKCHAIN_TOPIC.into()), serialized_block.as_bytes());
-                // Set text for balance from blockchain
-                self.curr_balance.set_text(BLOCKCHAIN.read().unwrap().get_balance(MY_PEER_ID.to_string()).to_string().as_str());
+                let receiver_peer = match PeerId::from_str(&checks[n]) {
+                    Ok(peer_id) => peer_id,
+                    Err(_) => {
+                        nwg::simple_message("Error", &format!("Could not complete transaction. {:?} is not a valid peer id", checks[n]));
+                        positive = false;
+                        break;
+                    }
+                };
+                // Add transaction to the mempool
+                let transaction = match create_transaction(receiver_peer, sent_amount_int as u64, DEFAULT_TRANSACTION_FEE) {
+                    Ok(transaction) => transaction,
+                    Err(error) => {
+                        nwg::simple_message("Error", &format!("Could not complete transaction. {}", error));
+                        positive = false;
+                        break;
+                    }
+                };
+                // Let the network loop send it to peers and mine it
+                if let Some(local_transactions) = &self.local_transactions {
+                    local_transactions.unbounded_send(transaction).expect("Network loop stopped");
+                }
 
             }
             if positive
@@ -248,15 +301,298 @@ impl MessageBank {
 }
 
 
+// Read the network's starting allocations and rules (block time, retarget interval, subsidy), every node on the network needs the same file
+// Without one we start a network where money only comes from mining, with the default rules
+fn load_genesis() -> Genesis {
+    let path = env::var("GENESIS_FILE").unwrap_or_else(|_| DEFAULT_GENESIS_FILE.to_string());
+    let genesis = match std::fs::read_to_string(&path) {
+        Ok(contents) => Genesis::from_json(&contents).expect("Failed to parse genesis file"),
+        Err(_) => {
+            eprintln!("No genesis file at {}, starting with no allocations", path);
+            Genesis::default()
+        }
+    };
+    genesis.validate().expect("Invalid genesis allocation");
+
+    genesis
+}
+
+fn data_dir() -> String {
+    env::var("DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.to_string())
+}
+
+// Keep blocks and accounts in memory unless STORAGE=disk, which keeps them in a database in the data directory
+fn use_disk_storage() -> bool {
+    match env::var("STORAGE").as_deref() {
+        Ok("disk") => true,
+        Ok("memory") | Err(_) => false,
+        Ok(other) => panic!("Unknown storage {:?}, expected memory or disk", other),
+    }
+}
+
+// The database already keeps every block, so the block store is only needed to rebuild in memory storage
+fn open_block_store() -> Option<BlockStore> {
+    if use_disk_storage() {
+        return None;
+    }
+
+    Some(BlockStore::open(std::path::Path::new(&data_dir()), &GENESIS.block().hash()).expect("Failed to open block store"))
+}
+
+fn open_storage() -> Box<dyn Storage> {
+    if use_disk_storage() {
+        let path = std::path::Path::new(&data_dir()).join(STORAGE_DIR);
+        Box::new(DiskStorage::open(&path).expect("Failed to open storage"))
+    } else {
+        Box::new(MemoryStorage::new())
+    }
+}
+
+// Pick up the chain from storage, or rebuild it by re-validating every block we saved last time
+fn load_blockchain() -> Blockchain {
+    let mut blockchain = Blockchain::with_storage(GENESIS.clone(), Target::from_leading_zero_bits(INITIAL_DIFFICULTY_BITS), open_storage())
+        .expect("Storage holds a chain from a different genesis");
+    // How far ahead of our clock a block can be is up to each node, unlike the rest of the rules (MAX_FUTURE_DRIFT, in seconds)
+    if let Some(seconds) = env::var("MAX_FUTURE_DRIFT").ok().and_then(|seconds| seconds.parse().ok()) {
+        blockchain.set_max_future_drift(chrono::Duration::seconds(seconds));
+    }
+    if blockchain.height() > 0 {
+        println!("Loaded chain from storage, height {}", blockchain.height());
+    } else if let Some(block_store) = BLOCK_STORE.lock().unwrap().as_ref() {
+        let blocks = block_store.read_blocks().expect("Failed to read block store");
+        let stored = blocks.len();
+        let mut rejected = 0;
+        for block in blocks {
+            if let Err(error) = blockchain.add_block(block) {
+                eprintln!("Skipping stored block: {}", error);
+                rejected += 1;
+            }
+        }
+        println!("Loaded {} stored blocks ({} rejected), height {}", stored, rejected, blockchain.latest_block().index());
+    }
+    // Replaying the whole chain from genesis takes longer the longer it gets, so only check what we loaded when asked to
+    // (VERIFY_CHAIN=1), for example after the database was copied in or cut off part way through a write
+    if env::var("VERIFY_CHAIN").is_ok() {
+        if let Err(invalid) = blockchain.validate() {
+            panic!("Loaded chain is invalid at block {}: {}", invalid.position, invalid.error);
+        }
+        println!("Verified chain, height {}", blockchain.height());
+    }
+
+    blockchain
+}
+
+// Add a block from a peer or our miner and save it, so a restart picks up from here
+fn add_block(block: Block) -> Result<ChainUpdate, BlockchainError> {
+    let mut blockchain = BLOCKCHAIN.write().unwrap();
+    let update = blockchain.add_block(block.clone())?;
+    if let Some(block_store) = BLOCK_STORE.lock().unwrap().as_mut() {
+        if let Err(error) = block_store.append(&block) {
+            eprintln!("Failed to save block: {}", error);
+        }
+    }
+
+    Ok(update)
+}
+
+// Create and sign a transaction from us, then add it to the mempool
+fn create_transaction(receiver: PeerId, amount: CurrencyType, fee: CurrencyType) -> Result<Transaction, BlockchainError> {
+    let mut mempool = MEMPOOL.write().unwrap();
+    let blockchain = BLOCKCHAIN.read().unwrap();
+    let nonce = mempool.next_nonce(&MY_PEER_ID.to_string(), &blockchain);
+    let mut transaction = Transaction::new(MY_PEER_ID.clone(), receiver, amount, fee, nonce);
+    transaction.sign(&MY_KEYPAIR).expect("Failed to sign transaction");
+    mempool.insert(transaction.clone(), &blockchain)?;
+
+    Ok(transaction)
+}
+
+// Send one of our transactions to the swarm
+fn publish_transaction(transaction: &Transaction) {
+    let message = NetworkMessage::NewTransaction(transaction.clone()).to_bytes();
+    SWARM.lock().unwrap().gossipsub.publish(&topic(&NETWORK_ID, TRANSACTION_TOPIC), message);
+}
+
+// Start mining a block from the mempool on another thread (while there's anything waiting, the block reward and fees pay us for it)
+// The result (None if it was cancelled) is sent back to the network loop, which adds and publishes it
+// Blocks with no transactions are only mined when asked for (to get money on a network that starts without any)
+fn start_mining(threads: usize, mined_blocks: UnboundedSender<Option<Block>>, allow_empty: bool) -> Option<Arc<Miner>> {
+    // Leave room for the coinbase
+    let transactions = MEMPOOL.read().unwrap().select_batch(MAX_BLOCK_TRANSACTIONS - 1);
+    if transactions.is_empty() && !allow_empty {
+        return None;
+    }
+
+    // Only hold the lock long enough to build the block, the search works on its own copy
+    let result = BLOCKCHAIN.read().unwrap().next_block(&MY_PEER_ID, transactions);
+    let block = match result {
+        Ok(block) => block,
+        Err(error) => {
+            eprintln!("Failed to build block: {}", error);
+            return None;
+        }
+    };
+    let miner = Arc::new(Miner::new(threads));
+    let job = miner.clone();
+    thread::spawn(move || {
+        let _ = mined_blocks.unbounded_send(job.mine(&block));
+    });
+
+    Some(miner)
+}
+
+// Add a block our miner found and send it to the swarm
+fn mined_block(block: Block) {
+    let message = NetworkMessage::NewBlock(block.clone()).to_bytes();
+    match add_block(block) {
+        Ok(update) => {
+            SWARM.lock().unwrap().gossipsub.publish(&topic(&NETWORK_ID, BLOCKCHAIN_TOPIC), message);
+            chain_updated(&update);
+        },
+        Err(error) => eprintln!("Failed to add mined block: {}", error),
+    }
+}
+
+// Drop mempool transactions the new blocks used up or invalidated, and refresh our balance
+fn chain_updated(update: &ChainUpdate) {
+    let mut mempool = MEMPOOL.write().unwrap();
+    let blockchain = BLOCKCHAIN.read().unwrap();
+    // Transactions from blocks that left the main chain need to be mined again (unless the new branch has them too)
+    for block in update.disconnected.iter().rev() {
+        // Coinbases only belong in the block that made them
+        for transaction in block.transactions().iter().filter(|transaction| !transaction.is_coinbase()) {
+            let _ = mempool.insert(transaction.clone(), &blockchain);
+        }
+    }
+    mempool.prune(&blockchain);
+    *MY_GUI_BAL.write().unwrap() = blockchain.get_balance(MY_PEER_ID.to_string());
+}
+
+// Add a block from a peer (rejecting it if it isn't valid, it may switch us to another branch)
+fn received_block(block: Block, current_miner: &Option<Arc<Miner>>) -> Result<ChainUpdate, BlockchainError> {
+    let update = add_block(block)?;
+    // Our tip moved, so whatever we're mining is stale
+    if !update.connected.is_empty() {
+        if let Some(miner) = current_miner {
+            miner.cancel();
+        }
+    }
+    if update.is_reorganization() {
+        println!("Switched branches, {} blocks disconnected and {} connected", update.disconnected.len(), update.connected.len());
+    }
+    chain_updated(&update);
+    if update.connected.iter().flat_map(Block::transactions).any(|transaction| transaction.receiver.eq(&MY_PEER_ID.to_string()))
+    {
+        println!("{:?}", MY_GUI_BAL.read().unwrap());
+    }
+
+    Ok(update)
+}
+
+// Answer a peer catching up from our main chain
+fn sync_response(request: SyncRequest) -> SyncResponse {
+    let blockchain = BLOCKCHAIN.read().unwrap();
+    match request {
+        SyncRequest::GetTip => SyncResponse::Tip(ChainTip::of(&blockchain)),
+        SyncRequest::GetHeaders { locator, max } => {
+            let locator = &locator[..locator.len().min(MAX_LOCATOR_HASHES)];
+            SyncResponse::Headers(blockchain.headers_after(locator, max.min(MAX_HEADERS)))
+        },
+        SyncRequest::GetBlocksByRange { start, count } => {
+            let blocks = (start..start.saturating_add(count.min(MAX_BLOCKS)))
+                .map(|height| blockchain.block_at_height(height))
+                .take_while(Option::is_some)
+                .flatten()
+                .collect();
+            SyncResponse::Blocks(blocks)
+        },
+        SyncRequest::GetBlockByHash(hash) => match blockchain.get_block(&hash) {
+            Some(block) => SyncResponse::Block(block),
+            None => SyncResponse::NotFound(hash),
+        },
+    }
+}
+
+// Write our main chain to a file as JSON for people to look through (nodes only ever read the binary encoding)
+fn export_chain(path: &str) -> std::io::Result<usize> {
+    let blockchain = BLOCKCHAIN.read().unwrap();
+    let blocks: Vec<Block> = (0..=blockchain.height()).filter_map(|height| blockchain.block_at_height(height)).collect();
+    std::fs::write(path, serde_json::to_vec_pretty(&blocks)?)?;
+
+    Ok(blocks.len())
+}
+
+// Compare how big our main chain is in the binary encoding against JSON
+fn print_encoding_sizes() {
+    let blockchain = BLOCKCHAIN.read().unwrap();
+    let (mut blocks, mut transactions, mut json_size, mut binary_size) = (0, 0, 0, 0);
+    for block in (0..=blockchain.height()).filter_map(|height| blockchain.block_at_height(height)) {
+        blocks += 1;
+        transactions += block.transactions().len();
+        json_size += serde_json::to_vec(&block).expect("Failed to serialize block").len();
+        binary_size += block.encode().len();
+    }
+    println!("{} blocks with {} transactions: JSON {} bytes, binary {} bytes ({:.1}% of JSON)",
+        blocks, transactions, json_size, binary_size, 100.0 * binary_size as f64 / json_size as f64);
+}
+
+// Add a block from a peer, first fetching its parent from them if we don't have it, then any blocks that were waiting on it
+fn handle_block(block: Block, peer_id: &PeerId, sync: &mut ChainSync, current_miner: &Option<Arc<Miner>>) -> Result<ChainUpdate, BlockchainError> {
+    if !BLOCKCHAIN.read().unwrap().has_block(&block.previous_hash()) {
+        send_sync_request(sync.missing_parent(peer_id.clone(), block));
+        return Err(BlockchainError::UnknownParent);
+    }
+    let hash = block.hash();
+    let result = received_block(block, current_miner);
+    match &result {
+        Ok(_) => {
+            for orphan in sync.take_orphans(&hash) {
+                let _ = handle_block(orphan, peer_id, sync, current_miner);
+            }
+        },
+        // Likely one we got while syncing or from another peer first
+        Err(BlockchainError::AlreadyKnown) => {},
+        Err(error) => eprintln!("Rejected block from {}: {}", peer_id, error),
+    }
+
+    result
+}
+
+// Check a gossiped transaction and hold on to it until it gets mined
+fn received_transaction(transaction: Transaction, peer_id: &PeerId) -> Validation {
+    let mut mempool = MEMPOOL.write().unwrap();
+    match mempool.insert(transaction, &BLOCKCHAIN.read().unwrap()) {
+        Ok(true) => Validation::Accept,
+        Ok(false) => Validation::Ignore,
+        Err(error) => {
+            eprintln!("Rejected transaction from {}: {}", peer_id, error);
+            match error {
+                // These depend on what we've seen so far, the transaction may be fine for peers that are ahead of us
+                BlockchainError::InvalidNonce { .. } | BlockchainError::InsufficientFunds { .. } | BlockchainError::MempoolFull => Validation::Ignore,
+                _ => Validation::Reject,
+            }
+        }
+    }
+}
+
+fn send_sync_request(request: Option<(PeerId, SyncRequest)>) {
+    if let Some((peer_id, request)) = request {
+        SWARM.lock().unwrap().sync.send_request(&peer_id, request);
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     let (tx, rx) = channel();
+    let (local_transactions, mut local_transaction_receiver) = unbounded();
+    let (mined_blocks, mut mined_block_receiver) = unbounded::<Option<Block>>();
+    let mining_threads = env::var("MINING_THREADS").ok().and_then(|threads| threads.parse().ok()).unwrap_or(DEFAULT_MINING_THREADS);
 
     thread::spawn(move || {
 
         nwg::init().expect("Failed to init Native Windows GUI");
         nwg::Font::set_global_family("Segoe UI").expect("Failed to set default font");
 
-        let _ui = MessageBank::build_ui(Default::default()).expect("Failed to build UI");
+        let _ui = MessageBank::build_ui(MessageBank { local_transactions: Some(local_transactions), ..Default::default() }).expect("Failed to build UI");
     
         loop {
             let receive_result = rx.try_recv();
@@ -299,11 +635,14 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // Get all previously known peers
     let known_peers = get_known_peers();
-    // Connect to all previously saved known peers
+    // Connect to all previously saved known peers, and start peer discovery from them
     for known_peer in known_peers.into_iter().take(MAX_PEERS) {
         let address = known_peer.ip();
+        add_known_peer(&mut *SWARM.lock().unwrap(), &known_peer.peer_id(), address.clone());
         dial_address(address, &mut *SWARM.lock().unwrap());
     }
+    // Without any known peers this waits until the peer we dialed shows up in the routing table
+    let mut bootstrapped = bootstrap(&mut *SWARM.lock().unwrap());
 
     // TODO: On exit or new peer connection save to file with ip from
 
@@ -316,29 +655,55 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut stdin = io::BufReader::new(io::stdin()).lines();
 
     let mut listening = false;
+    // The block we're currently mining, if any (only one at a time)
+    let mut current_miner: Option<Arc<Miner>> = None;
+    // Set by the mine command to mine the next block even if there's nothing to put in it
+    let mut mine_empty = false;
+    // Catching up to peers that are ahead of us, gossiped blocks wait until it's done
+    let mut sync = ChainSync::default();
     task::block_on(future::poll_fn(move |cx: &mut Context<'_>| {
         loop {
             match stdin.try_poll_next_unpin(cx)? {
                 Poll::Ready(Some(line)) => {
                     if line.starts_with("send ") {
-                        let mut tokens = line.split_ascii_whitespace();
-                        let amount_str = tokens.next().expect("send requires an amount");
-                        let receiver_str = tokens.next().expect("send requires a receiver");
-                        let amount = amount_str.parse().expect("invalid amount in send (expected a valid number)");
-                        let receiver_peer = PeerId::from_str(receiver_str).expect("invalid peer id in send");
-
-                        // Create transaction
-                        let transaction = Transaction::new(MY_PEER_ID.clone(), receiver_peer, amount);
-                        // Update local blockchain
-                        BLOCKCHAIN.write().unwrap().add_transaction(transaction);
-
-                        // Send to the rest of the swarm
-                        let serialized_block = serde_json::to_string(BLOCKCHAIN.read().unwrap().latest_block()).expect("Failed to serialize block");
-                        SWARM.lock().unwrap().publish(&Topic::new(BLOCKCHAIN_TOPIC.into()), serialized_block.as_bytes());
+                        let mut tokens = line.split_ascii_whitespace().skip(1);
+                        let amount = tokens.next().and_then(|amount_str| amount_str.parse().ok());
+                        let receiver_peer = tokens.next().and_then(|receiver_str| PeerId::from_str(receiver_str).ok());
+                        let fee = match tokens.next() {
+                            Some(fee_str) => fee_str.parse().ok(),
+                            None => Some(DEFAULT_TRANSACTION_FEE),
+                        };
+                        match (amount, receiver_peer, fee) {
+                            (Some(amount), Some(receiver_peer), Some(fee)) => match create_transaction(receiver_peer, amount, fee) {
+                                // Send to the rest of the swarm (it gets mined below)
+                                Ok(transaction) => publish_transaction(&transaction),
+                                Err(error) => eprintln!("Transaction failed: {}", error),
+                            },
+                            _ => eprintln!("Usage: send <amount> <peer id> [fee]"),
+                        }
                     } else if line.starts_with("bal ") {
                         println!("Balance: ${}", BLOCKCHAIN.read().unwrap().get_balance(line[4..].into()));
                     } else if line == "bal" {
                         println!("Balance: ${}", BLOCKCHAIN.read().unwrap().get_balance(MY_PEER_ID.to_string()));
+                    } else if line == "mine" {
+                        mine_empty = true;
+                    } else if line == "hashrate" {
+                        match &current_miner {
+                            Some(miner) => println!("Mining at {:.0} H/s on {} threads", miner.hashrate(), miner.threads()),
+                            None => println!("Not mining"),
+                        }
+                    } else if line == "bench" {
+                        // Hash a block on our tip for a few seconds each way (only one thread, so it's comparable between machines)
+                        let block = BLOCKCHAIN.read().unwrap().next_block(&MY_PEER_ID, Vec::new()).expect("Empty block should be valid");
+                        let (full_rate, midstate_rate) = benchmark(&block, std::time::Duration::from_secs(BENCHMARK_SECONDS));
+                        println!("Full header: {:.0} H/s, midstate: {:.0} H/s ({:.2}x)", full_rate, midstate_rate, midstate_rate / full_rate);
+                    } else if line == "sizes" {
+                        print_encoding_sizes();
+                    } else if line.starts_with("export ") {
+                        match export_chain(line[7..].trim()) {
+                            Ok(count) => println!("Exported {} blocks to {}", count, line[7..].trim()),
+                            Err(error) => eprintln!("Export failed: {}", error),
+                        }
                     } else {
                         eprintln!("Unknown command");
                     }
@@ -348,34 +713,160 @@ fn main() -> Result<(), Box<dyn Error>> {
             }
         }
 
+        // Transactions made in the GUI
+        while let Poll::Ready(Some(transaction)) = local_transaction_receiver.poll_next_unpin(cx) {
+            publish_transaction(&transaction);
+        }
+
         loop {
-            match SWARM.lock().unwrap().poll_next_unpin(cx) {
-                Poll::Ready(Some(gossip_event)) => match gossip_event {
+            // Release the swarm before handling the event so we can publish from inside it
+            let event = SWARM.lock().unwrap().poll_next_unpin(cx);
+            match event {
+                Poll::Ready(Some(NodeEvent::Gossip(gossip_event))) => match gossip_event {
                     GossipsubEvent::Message(peer_id, id, message) => {
-                        let topic = message.topics.first().unwrap().as_str();
-                        if topic == BLOCKCHAIN_TOPIC {
-                            // Parse incoming message as a block
-                            let block = serde_json::from_slice(message.data.as_slice()).expect("Failed to parse incoming message");
-                            // Add block
-                            BLOCKCHAIN.write().unwrap().add_block(block);
-                            if BLOCKCHAIN.read().unwrap().latest_block().transaction().receiver.eq(&MY_PEER_ID.to_string())
-                            {
-                                *MY_GUI_BAL.write().unwrap() = BLOCKCHAIN.read().unwrap().get_balance(MY_PEER_ID.to_string());
-                                println!("{:?}", MY_GUI_BAL.read().unwrap());
-                            }
-                        }
-                        // TODO: Use another topic to send whole blockchain to new peers so they're up to date (if we have time)
+                        // Check the message before gossipsub passes it on to anyone else
+                        let validation = match NetworkMessage::from_bytes(message.data.as_slice()) {
+                            // Not their fault if they're on another version, just don't pass it on
+                            Err(MessageError::UnsupportedVersion(_)) => Validation::Ignore,
+                            Err(error) => {
+                                eprintln!("Failed to parse message from {}: {}", peer_id, error);
+                                Validation::Reject
+                            },
+                            // Only a peer can announce its own tip (messages are signed by whoever wrote them)
+                            Ok(NetworkMessage::Announce(announce)) => match PeerId::from_str(&announce.peer_id) {
+                                Ok(announcer) if message.source.as_ref() == Some(&announcer) => {
+                                    let next = sync.tip_received(announcer, announce.tip, &BLOCKCHAIN.read().unwrap());
+                                    send_sync_request(next);
+                                    Validation::Accept
+                                },
+                                _ => Validation::Reject,
+                            },
+                            Ok(NetworkMessage::NewTransaction(transaction)) => received_transaction(transaction, &peer_id),
+                            // Blocks that arrive while we're catching up may not connect to our chain yet, so they can't be checked
+                            Ok(NetworkMessage::NewBlock(block)) if sync.is_syncing() => {
+                                sync.defer_block(peer_id.clone(), block);
+                                Validation::Ignore
+                            },
+                            Ok(NetworkMessage::NewBlock(block)) => match handle_block(block, &peer_id, &mut sync, &current_miner) {
+                                Ok(update) if !update.connected.is_empty() => Validation::Accept,
+                                // Valid but on a side branch, which nobody needs passed on until it has the most work
+                                Ok(_) => Validation::Ignore,
+                                // Nothing wrong with the block as far as we can tell yet (or our clock is behind)
+                                Err(BlockchainError::AlreadyKnown) | Err(BlockchainError::UnknownParent) | Err(BlockchainError::TimestampTooNew { .. }) => Validation::Ignore,
+                                // Could just be a peer that's been cut off for a while, but there's no point passing it on
+                                Err(BlockchainError::ForkTooDeep { .. }) => Validation::Ignore,
+                                Err(_) => Validation::Reject,
+                            },
+                        };
+                        report_validation(&mut *SWARM.lock().unwrap(), &id, &peer_id, validation);
                     },
-                    GossipsubEvent::Subscribed{peer_id, ..} => {
+                    GossipsubEvent::Subscribed{peer_id, topic: subscribed_topic} => {
+                        // Ask just the new peer where its chain ends in case we're behind (it does the same for us)
+                        if subscribed_topic == topic(&NETWORK_ID, ANNOUNCE_TOPIC).no_hash() {
+                            send_sync_request(Some((peer_id.clone(), SyncRequest::GetTip)));
+                        }
                         tx.send(peer_id).unwrap();
                     },
                     _ => {}
                     
                 },
+                Poll::Ready(Some(NodeEvent::Sync(sync_event))) => match sync_event {
+                    RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                        let response = sync_response(request);
+                        if let SyncResponse::Blocks(blocks) = &response {
+                            println!("Sending {} blocks to {}", blocks.len(), peer);
+                        }
+                        SWARM.lock().unwrap().sync.send_response(channel, response);
+                    },
+                    RequestResponseEvent::Message { peer, message: RequestResponseMessage::Response { response, .. } } => {
+                        let next = match response {
+                            SyncResponse::Tip(tip) => sync.tip_received(peer, tip, &BLOCKCHAIN.read().unwrap()),
+                            SyncResponse::Headers(headers) => sync.headers_received(peer, headers, &BLOCKCHAIN.read().unwrap()),
+                            SyncResponse::Blocks(blocks) => match sync.blocks_received(peer.clone(), blocks) {
+                                Some(blocks) => {
+                                    // A block we got through gossip in the meantime is fine, anything else means the peer lied to us
+                                    let mut all_added = true;
+                                    let mut orphans = Vec::new();
+                                    for block in blocks {
+                                        let hash = block.hash();
+                                        match received_block(block, &current_miner) {
+                                            Ok(_) | Err(BlockchainError::AlreadyKnown) => orphans.extend(sync.take_orphans(&hash)),
+                                            Err(error) => {
+                                                eprintln!("Rejected synced block from {}: {}", peer, error);
+                                                all_added = false;
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    for orphan in orphans {
+                                        let _ = handle_block(orphan, &peer, &mut sync, &current_miner);
+                                    }
+                                    let blockchain = BLOCKCHAIN.read().unwrap();
+                                    println!("Synced to height {}", blockchain.height());
+                                    sync.blocks_added(all_added, &blockchain)
+                                },
+                                None => None,
+                            },
+                            SyncResponse::Block(block) => {
+                                if let Some(block) = sync.parent_received(block) {
+                                    let _ = handle_block(block, &peer, &mut sync, &current_miner);
+                                }
+                                None
+                            },
+                            SyncResponse::NotFound(hash) => {
+                                sync.parent_not_found(&hash);
+                                None
+                            },
+                        };
+                        send_sync_request(next);
+                    },
+                    RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                        eprintln!("Sync request to {} failed: {:?}", peer, error);
+                        let next = sync.request_failed(&peer, &BLOCKCHAIN.read().unwrap());
+                        send_sync_request(next);
+                    },
+                    RequestResponseEvent::InboundFailure { .. } => {},
+                },
+                Poll::Ready(Some(NodeEvent::Discovery(discovery_event))) => match discovery_event {
+                    // The first peer we connect to (like the one we were told to dial) is enough to start discovering the rest
+                    KademliaEvent::RoutingUpdated { .. } if !bootstrapped => {
+                        bootstrapped = bootstrap(&mut *SWARM.lock().unwrap());
+                    },
+                    // Peers found by a random walk
+                    KademliaEvent::QueryResult { result: QueryResult::GetClosestPeers(Ok(GetClosestPeersOk { peers, .. })), .. } => {
+                        let mut swarm = SWARM.lock().unwrap();
+                        for peer_id in peers.iter() {
+                            dial_discovered_peer(&mut *swarm, peer_id, MAX_PEERS);
+                        }
+                    },
+                    _ => {},
+                },
                 Poll::Ready(None) | Poll::Pending => break,
             }
         }
 
+        // Gossiped blocks that came in while we were catching up
+        for (peer_id, block) in sync.take_deferred() {
+            let _ = handle_block(block, &peer_id, &mut sync, &current_miner);
+        }
+
+        // Blocks our miner finished (or gave up on)
+        while let Poll::Ready(Some(mined)) = mined_block_receiver.poll_next_unpin(cx) {
+            if let (Some(block), Some(miner)) = (mined, &current_miner) {
+                println!("Mined block {} at {:.0} H/s", block.index(), miner.hashrate());
+                mined_block(block);
+            }
+            current_miner = None;
+        }
+
+        // Put any of our waiting transactions into a block (once we're caught up, anything before that would be wasted)
+        if current_miner.is_none() && !sync.is_syncing() {
+            current_miner = start_mining(mining_threads, mined_blocks.clone(), mine_empty);
+            if current_miner.is_some() {
+                mine_empty = false;
+            }
+        }
+
         if !listening {
             for address in libp2p::Swarm::listeners(&*SWARM.lock().unwrap()) {
                 println!("Listening on {:?}", address);
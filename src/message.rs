@@ -0,0 +1,147 @@
+// Local imports
+use crate::blockchain::{put_peer_id, put_varint, Block, Decode, DecodeError, Encode, HASH_SIZE, Reader, Transaction};
+use crate::sync::ChainTip;
+// Std imports
+use std::error::Error;
+use std::fmt;
+// External imports
+use chrono::{DateTime, TimeZone, Utc};
+use sha2::{Sha256, Digest};
+
+// Bumped whenever messages change in a way older nodes can't read (it's part of the topic names too)
+// Version 2 switched from JSON to the binary encoding
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// What kind of message follows the version
+const NEW_BLOCK_TAG: u8 = 0;
+const NEW_TRANSACTION_TAG: u8 = 1;
+const ANNOUNCE_TAG: u8 = 2;
+
+// A peer telling the network where its chain ends, so anyone behind it can sync from it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub peer_id: String,
+    pub tip: ChainTip,
+    // Keeps repeat announcements of the same tip from being dropped as duplicates
+    pub timestamp: DateTime<Utc>,
+}
+
+// Everything we gossip, tagged with what it carries so peers know what to do with it
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkMessage {
+    NewBlock(Block),
+    NewTransaction(Transaction),
+    Announce(Announce),
+}
+
+impl Encode for Announce {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        put_peer_id(bytes, &self.peer_id);
+        self.tip.encode_to(bytes);
+        bytes.extend_from_slice(&self.timestamp.timestamp_nanos().to_le_bytes());
+    }
+}
+
+impl Decode for Announce {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Self {
+            peer_id: reader.peer_id()?,
+            tip: ChainTip::decode_from(reader)?,
+            timestamp: Utc.timestamp_nanos(reader.u64()? as i64),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    // Not a message at all
+    Malformed(DecodeError),
+    // A message from a version of the protocol we don't speak
+    UnsupportedVersion(u64),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Malformed(error) => write!(f, "malformed message: {}", error),
+            MessageError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {} (we speak {})", version, PROTOCOL_VERSION),
+        }
+    }
+}
+
+impl Error for MessageError {}
+
+// Hex string of some bytes (for hashes in logs and message ids)
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl NetworkMessage {
+    // What's actually sent, the protocol version the message was written for followed by the message
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_varint(&mut bytes, PROTOCOL_VERSION as u64);
+        match self {
+            NetworkMessage::NewBlock(block) => {
+                bytes.push(NEW_BLOCK_TAG);
+                block.encode_to(&mut bytes);
+            },
+            NetworkMessage::NewTransaction(transaction) => {
+                bytes.push(NEW_TRANSACTION_TAG);
+                transaction.encode_to(&mut bytes);
+            },
+            NetworkMessage::Announce(announce) => {
+                bytes.push(ANNOUNCE_TAG);
+                announce.encode_to(&mut bytes);
+            },
+        }
+
+        bytes
+    }
+
+    // The version is read first so a message from a newer version isn't mistaken for a malformed one
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MessageError> {
+        let mut reader = Reader::new(bytes);
+        let version = reader.varint().map_err(MessageError::Malformed)?;
+        if version != PROTOCOL_VERSION as u64 {
+            return Err(MessageError::UnsupportedVersion(version));
+        }
+        let message = match reader.u8().map_err(MessageError::Malformed)? {
+            NEW_BLOCK_TAG => Block::decode_from(&mut reader).map(NetworkMessage::NewBlock),
+            NEW_TRANSACTION_TAG => Transaction::decode_from(&mut reader).map(NetworkMessage::NewTransaction),
+            ANNOUNCE_TAG => Announce::decode_from(&mut reader).map(NetworkMessage::Announce),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }.map_err(MessageError::Malformed)?;
+        reader.finish().map_err(MessageError::Malformed)?;
+
+        Ok(message)
+    }
+
+    // Identifies what the message is about rather than how it was encoded, so the same block or transaction
+    // always gets the same id (the kind is hashed in too so a block and a transaction can never collide)
+    // None for a block whose transactions don't match its header, otherwise a copy of a real block's header
+    // with junk transactions would take the real block's id and get it dropped as a duplicate
+    pub fn id(&self) -> Option<[u8; HASH_SIZE]> {
+        let mut hasher = Sha256::new();
+        match self {
+            NetworkMessage::NewBlock(block) => {
+                if !block.has_valid_merkle_root() {
+                    return None;
+                }
+                hasher.update(b"block");
+                // Hash the header ourselves rather than trusting the hash it claims
+                hasher.update(block.calculate_hash());
+            },
+            NetworkMessage::NewTransaction(transaction) => {
+                hasher.update(b"transaction");
+                hasher.update(transaction.hash());
+            },
+            NetworkMessage::Announce(announce) => {
+                hasher.update(b"announce");
+                hasher.update(announce.encode());
+            },
+        }
+
+        Some(hasher.finalize().into())
+    }
+}
@@ -4,13 +4,14 @@ use std::path::Path;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write, Result as IOResult};
 use std::str::FromStr;
+// Local imports
+use crate::swarm::NodeBehaviour;
 // External imports
 use libp2p::{PeerId, Swarm};
 use libp2p::identity::{Keypair};
 use libp2p::websocket::tls::PrivateKey;
 use libp2p::identity::secp256k1::SecretKey;
 use libp2p::identity::secp256k1::PublicKey;
-use libp2p::gossipsub::Gossipsub;
 use libp2p::swarm::NetworkBehaviour;
 use libp2p::core::Multiaddr;
 use serde::{Serialize, Deserialize};
@@ -46,7 +47,7 @@ impl PeerData {
         self.ip.parse().expect("Failed to parse peer multi address")
     }
 
-    pub fn new(peer_id: &PeerId, swarm: &mut Swarm<Gossipsub>) -> Self {
+    pub fn new(peer_id: &PeerId, swarm: &mut Swarm<NodeBehaviour>) -> Self {
         let multi_address = swarm.addresses_of_peer(&peer_id).first().expect("Peer found without IP").to_string();
         Self {
             id: peer_id.to_string(),
@@ -1,28 +1,131 @@
 
+// Local imports
+use crate::message::{hex, NetworkMessage, PROTOCOL_VERSION};
+use crate::sync::{SyncCodec, SyncProtocol, SyncRequest, SyncResponse};
 // Std imports
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::iter;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 // External imports
-use libp2p::gossipsub::{GossipsubMessage, GossipsubConfigBuilder, MessageAuthenticity, Gossipsub, MessageId, Topic};
-use libp2p::{Swarm, PeerId};
+use async_std::task;
+use libp2p::gossipsub::{GossipsubEvent, GossipsubMessage, GossipsubConfigBuilder, MessageAuthenticity, Gossipsub, MessageId, Topic};
+use libp2p::kad::{Kademlia, KademliaConfig, KademliaEvent};
+use libp2p::kad::record::store::MemoryStore;
+use libp2p::request_response::{ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent};
+use libp2p::swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters};
+use libp2p::{NetworkBehaviour, Swarm, PeerId};
 use libp2p::identity::Keypair;
 use libp2p::core::Multiaddr;
+use sha2::{Sha256, Digest};
 
 pub const BLOCKCHAIN_TOPIC: &'static str = "blockchain";
-pub const IDENTIFY_TOPIC: &'static str = "identify";
+pub const ANNOUNCE_TOPIC: &'static str = "announce";
+pub const TRANSACTION_TOPIC: &'static str = "transactions";
+
+// How many messages we reject from a peer before banning it
+const MAX_REJECTED_MESSAGES: u32 = 5;
+
+// How long a peer has to answer a sync request before we give up on it
+const SYNC_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a connection used only for sync requests stays open with nothing going on
+const SYNC_KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+// How often we look up a random peer id to find peers we haven't met yet
+const RANDOM_WALK_INTERVAL: Duration = Duration::from_secs(30);
+
+// What we made of a gossiped message after checking it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    // Valid, pass it on to the rest of the mesh
+    Accept,
+    // Invalid, don't pass it on and hold it against whoever sent it
+    Reject,
+    // Can't tell or not worth passing on (already have it, or it depends on something we don't have yet)
+    Ignore,
+}
+
+// Everything the network loop has to handle, from either protocol
+#[derive(Debug)]
+pub enum NodeEvent {
+    Gossip(GossipsubEvent),
+    Sync(RequestResponseEvent<SyncRequest, SyncResponse>),
+    Discovery(KademliaEvent),
+}
+
+// Gossip for new blocks and transactions, plus requests to a single peer for catching up on blocks we missed
+// and a DHT for finding peers beyond the ones we were told about
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "NodeEvent", poll_method = "poll_events")]
+pub struct NodeBehaviour {
+    pub gossipsub: Gossipsub,
+    pub sync: RequestResponse<SyncCodec>,
+    pub kademlia: Kademlia<MemoryStore>,
+    // Fires when it's time for the next random walk
+    #[behaviour(ignore)]
+    random_walk: Pin<Box<dyn Future<Output = ()> + Send>>,
+    // Events from either protocol waiting to be handed to the network loop
+    #[behaviour(ignore)]
+    events: VecDeque<NodeEvent>,
+    // How many invalid messages each peer has sent us
+    #[behaviour(ignore)]
+    rejected: HashMap<PeerId, u32>,
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for NodeBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        self.events.push_back(NodeEvent::Gossip(event));
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<SyncRequest, SyncResponse>> for NodeBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<SyncRequest, SyncResponse>) {
+        self.events.push_back(NodeEvent::Sync(event));
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for NodeBehaviour {
+    fn inject_event(&mut self, event: KademliaEvent) {
+        self.events.push_back(NodeEvent::Discovery(event));
+    }
+}
+
+impl NodeBehaviour {
+    fn poll_events<TEvent>(&mut self, cx: &mut Context<'_>, _: &mut impl PollParameters) -> Poll<NetworkBehaviourAction<TEvent, NodeEvent>> {
+        // Looking up a random peer id walks us through parts of the network we haven't seen, the peers it finds come back as discovery events
+        while let Poll::Ready(()) = self.random_walk.as_mut().poll(cx) {
+            self.kademlia.get_closest_peers(PeerId::random());
+            self.random_walk = Box::pin(task::sleep(RANDOM_WALK_INTERVAL));
+            // Kademlia was already polled this time around, make sure it gets polled again to start the query
+            cx.waker().wake_by_ref();
+        }
+
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)),
+            None => Poll::Pending,
+        }
+    }
+}
 
 // What aspect of a message makes it unique (that way we don't repeat unnecessarily)
+// Messages are identified by the block or transaction they carry, anything that doesn't parse (or is a block whose
+// transactions don't match its header) by its raw bytes (it gets rejected anyway)
 fn message_hasher(message: &GossipsubMessage) -> MessageId {
-    // TODO: Just use block hash instead of message hash
-
+    let id: [u8; 32] = match NetworkMessage::from_bytes(&message.data).ok().and_then(|network_message| network_message.id()) {
+        Some(id) => id,
+        None => Sha256::digest(&message.data).into(),
+    };
+    MessageId::from(hex(&id))
+}
 
-    // Hash the message to get an unique id for it
-    let mut s = DefaultHasher::new();
-    message.data.hash(&mut s);
-    MessageId::from(s.finish().to_string())
+// Topic names include the network and protocol version, so test networks and incompatible nodes never hear each other
+pub fn topic(network_id: &str, name: &str) -> Topic {
+    Topic::new(format!("/p2p-money/{}/{}/{}", network_id, PROTOCOL_VERSION, name))
 }
 
-pub fn spawn_swarm(keypair: Keypair, peer_id: PeerId) -> Swarm<Gossipsub> {
+pub fn spawn_swarm(keypair: Keypair, peer_id: PeerId, network_id: &str) -> Swarm<NodeBehaviour> {
     // How we verify who sent a message
     let auth = MessageAuthenticity::Signed(keypair.clone());
 
@@ -30,27 +133,99 @@ pub fn spawn_swarm(keypair: Keypair, peer_id: PeerId) -> Swarm<Gossipsub> {
     let transport = libp2p::build_development_transport(keypair).expect("Failed to create transport channel");
 
     // Create a configuration for the network on how to handle messages, timeouts, peers, and more
+    // Messages are only forwarded once we've checked them (see report_validation)
     let config = GossipsubConfigBuilder::new()
         .message_id_fn(message_hasher)
+        .validate_messages()
         .build();
 
-    // Create the network behavior given the auth method and config
-    let mut behavior = Gossipsub::new(auth, config);
+    // Create the gossip behavior given the auth method and config
+    let mut gossipsub = Gossipsub::new(auth, config);
 
     // The blockchain topic, where all new transactions are transported
-    let blockchain_topic = Topic::new(BLOCKCHAIN_TOPIC.into());
-    behavior.subscribe(blockchain_topic);
+    let blockchain_topic = topic(network_id, BLOCKCHAIN_TOPIC);
+    gossipsub.subscribe(blockchain_topic);
+
+    // The transaction topic, where pending transactions are shared until someone mines them
+    let transaction_topic = topic(network_id, TRANSACTION_TOPIC);
+    gossipsub.subscribe(transaction_topic);
 
-    // Identify topic when a new peer connects
-    let identify_topic = Topic::new(IDENTIFY_TOPIC.into());
-    behavior.subscribe(identify_topic);
+    // The announce topic, where peers can say where their chains end (subscribing to it is also how we spot peers on our network to ask for their tip)
+    let announce_topic = topic(network_id, ANNOUNCE_TOPIC);
+    gossipsub.subscribe(announce_topic);
+
+    // Requests for blocks and headers, sent to one peer at a time while catching up or filling in a missing parent
+    let mut sync_config = RequestResponseConfig::default();
+    sync_config.set_request_timeout(SYNC_REQUEST_TIMEOUT);
+    sync_config.set_connection_keep_alive(SYNC_KEEP_ALIVE);
+    let sync = RequestResponse::new(SyncCodec, iter::once((SyncProtocol, ProtocolSupport::Full)), sync_config);
+
+    // Peer discovery, on its own protocol name per network so we only find peers on the same network
+    let mut kademlia_config = KademliaConfig::default();
+    kademlia_config.set_protocol_name(format!("/p2p-money/{}/kad/1", network_id).into_bytes());
+    let kademlia = Kademlia::with_config(peer_id.clone(), MemoryStore::new(peer_id.clone()), kademlia_config);
+
+    let behavior = NodeBehaviour {
+        gossipsub,
+        sync,
+        kademlia,
+        random_walk: Box::pin(task::sleep(RANDOM_WALK_INTERVAL)),
+        events: VecDeque::new(),
+        rejected: HashMap::new(),
+    };
 
     libp2p::Swarm::new(transport, behavior, peer_id)
 }
 
-pub fn dial_address(address: Multiaddr, swarm: &mut Swarm<Gossipsub>) {
+pub fn dial_address(address: Multiaddr, swarm: &mut Swarm<NodeBehaviour>) {
     match libp2p::Swarm::dial_addr(swarm, address.clone()) {
         Ok(_) => println!("Dialed {:?}", address.to_string()),
         Err(e) => eprintln!("Dial {:?} failed: {:?}", address.to_string(), e),
     }
 }
+
+// Let the DHT know where to find a peer, so it can be used to find others
+pub fn add_known_peer(swarm: &mut Swarm<NodeBehaviour>, peer_id: &PeerId, address: Multiaddr) {
+    swarm.kademlia.add_address(peer_id, address);
+}
+
+// Fill in our routing table by looking ourselves up through the peers we know, false if we don't know any yet
+pub fn bootstrap(swarm: &mut Swarm<NodeBehaviour>) -> bool {
+    swarm.kademlia.bootstrap().is_ok()
+}
+
+// Connect to a peer the DHT found, as long as we have room for it
+pub fn dial_discovered_peer(swarm: &mut Swarm<NodeBehaviour>, peer_id: &PeerId, max_peers: usize) {
+    if peer_id == libp2p::Swarm::local_peer_id(swarm) || libp2p::Swarm::connection_info(swarm, peer_id).is_some() {
+        return;
+    }
+    if libp2p::Swarm::network_info(swarm).num_peers >= max_peers {
+        return;
+    }
+    match libp2p::Swarm::dial(swarm, peer_id) {
+        Ok(_) => println!("Dialed discovered peer {}", peer_id),
+        Err(e) => eprintln!("Dial {} failed: {:?}", peer_id, e),
+    }
+}
+
+// Tell gossipsub what we made of a message from propagation_source, only accepted messages get forwarded
+// Peers that keep sending invalid messages get banned
+pub fn report_validation(swarm: &mut Swarm<NodeBehaviour>, message_id: &MessageId, propagation_source: &PeerId, validation: Validation) {
+    match validation {
+        Validation::Accept => {
+            // False if the message already fell out of gossipsub's cache, in which case it's too late to pass it on
+            if !swarm.gossipsub.validate_message(message_id, propagation_source) {
+                eprintln!("Message {} expired before it could be forwarded", message_id);
+            }
+        },
+        Validation::Reject => {
+            let rejected = swarm.rejected.entry(propagation_source.clone()).or_insert(0);
+            *rejected += 1;
+            if *rejected >= MAX_REJECTED_MESSAGES {
+                eprintln!("Banning {} after {} invalid messages", propagation_source, rejected);
+                libp2p::Swarm::ban_peer_id(swarm, propagation_source.clone());
+            }
+        },
+        Validation::Ignore => {},
+    }
+}
@@ -0,0 +1,633 @@
+// Local imports
+use crate::blockchain::{put_varint, Block, BlockHeader, Blockchain, Decode, DecodeError, Encode, Reader, HASH_SIZE};
+// Std imports
+use std::collections::{HashMap, VecDeque};
+use std::io;
+// External imports
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::PeerId;
+use libp2p::core::upgrade::{read_one, write_one};
+use libp2p::request_response::{ProtocolName, RequestResponseCodec};
+
+type BlockHash = [u8; HASH_SIZE];
+
+// Most headers sent in one response
+pub const MAX_HEADERS: u64 = 500;
+// Most blocks sent in one response (blocks are much bigger than headers)
+pub const MAX_BLOCKS: u64 = 16;
+// Most locator hashes we look through for a peer (a locator for a billion blocks is under 50)
+pub const MAX_LOCATOR_HASHES: usize = 64;
+// Largest request we'll read, requests are at most a locator
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+// Largest response we'll read, enough for MAX_BLOCKS full blocks
+const MAX_RESPONSE_SIZE: usize = 8 * 1024 * 1024;
+// Most gossiped blocks held on to while syncing, anything past this we'll get from the sync anyway
+const MAX_DEFERRED_BLOCKS: usize = 64;
+// Most gossiped blocks held on to while we fetch their parents, past this we're far enough behind to sync instead
+const MAX_ORPHANS: usize = 32;
+
+// Where a peer's main chain ends
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainTip {
+    pub height: u64,
+    pub hash: BlockHash,
+    pub total_work: u128,
+}
+
+impl ChainTip {
+    pub fn of(blockchain: &Blockchain) -> Self {
+        Self {
+            height: blockchain.height(),
+            hash: *blockchain.latest_block().hash(),
+            total_work: blockchain.total_work(),
+        }
+    }
+}
+
+impl Encode for ChainTip {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        put_varint(bytes, self.height);
+        self.hash.encode_to(bytes);
+        bytes.extend_from_slice(&self.total_work.to_le_bytes());
+    }
+}
+
+impl Decode for ChainTip {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        Ok(Self {
+            height: reader.varint()?,
+            hash: reader.hash()?,
+            total_work: reader.u128()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncRequest {
+    // Where the peer's main chain ends
+    GetTip,
+    // Main chain headers after the first locator hash the peer has (see Blockchain::locator)
+    GetHeaders { locator: Vec<BlockHash>, max: u64 },
+    // Main chain blocks starting at a height
+    GetBlocksByRange { start: u64, count: u64 },
+    // Any block the peer has, in its main chain or not
+    GetBlockByHash(BlockHash),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncResponse {
+    Tip(ChainTip),
+    Headers(Vec<BlockHeader>),
+    Blocks(Vec<Block>),
+    Block(Block),
+    // The peer doesn't have the block with this hash
+    NotFound(BlockHash),
+}
+
+// Requests and responses are a tag for which one it is followed by its fields
+impl Encode for SyncRequest {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        match self {
+            SyncRequest::GetTip => bytes.push(0),
+            SyncRequest::GetHeaders { locator, max } => {
+                bytes.push(1);
+                locator.encode_to(bytes);
+                put_varint(bytes, *max);
+            },
+            SyncRequest::GetBlocksByRange { start, count } => {
+                bytes.push(2);
+                put_varint(bytes, *start);
+                put_varint(bytes, *count);
+            },
+            SyncRequest::GetBlockByHash(hash) => {
+                bytes.push(3);
+                hash.encode_to(bytes);
+            },
+        }
+    }
+}
+
+impl Decode for SyncRequest {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match reader.u8()? {
+            0 => Ok(SyncRequest::GetTip),
+            1 => Ok(SyncRequest::GetHeaders { locator: Vec::decode_from(reader)?, max: reader.varint()? }),
+            2 => Ok(SyncRequest::GetBlocksByRange { start: reader.varint()?, count: reader.varint()? }),
+            3 => Ok(SyncRequest::GetBlockByHash(reader.hash()?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+impl Encode for SyncResponse {
+    fn encode_to(&self, bytes: &mut Vec<u8>) {
+        match self {
+            SyncResponse::Tip(tip) => {
+                bytes.push(0);
+                tip.encode_to(bytes);
+            },
+            SyncResponse::Headers(headers) => {
+                bytes.push(1);
+                headers.encode_to(bytes);
+            },
+            SyncResponse::Blocks(blocks) => {
+                bytes.push(2);
+                blocks.encode_to(bytes);
+            },
+            SyncResponse::Block(block) => {
+                bytes.push(3);
+                block.encode_to(bytes);
+            },
+            SyncResponse::NotFound(hash) => {
+                bytes.push(4);
+                hash.encode_to(bytes);
+            },
+        }
+    }
+}
+
+impl Decode for SyncResponse {
+    fn decode_from(reader: &mut Reader<'_>) -> Result<Self, DecodeError> {
+        match reader.u8()? {
+            0 => Ok(SyncResponse::Tip(ChainTip::decode_from(reader)?)),
+            1 => Ok(SyncResponse::Headers(Vec::decode_from(reader)?)),
+            2 => Ok(SyncResponse::Blocks(Vec::decode_from(reader)?)),
+            3 => Ok(SyncResponse::Block(Block::decode_from(reader)?)),
+            4 => Ok(SyncResponse::NotFound(reader.hash()?)),
+            tag => Err(DecodeError::InvalidTag(tag)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    // Version 2 switched from JSON to the binary encoding
+    fn protocol_name(&self) -> &[u8] {
+        b"/p2p-money/sync/2"
+    }
+}
+
+// Requests and responses are sent length prefixed in the binary encoding
+#[derive(Debug, Clone)]
+pub struct SyncCodec;
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+        where T: AsyncRead + Unpin + Send
+    {
+        let bytes = read_one(io, MAX_REQUEST_SIZE).await.map_err(invalid_data)?;
+        SyncRequest::decode(&bytes).map_err(invalid_data)
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+        where T: AsyncRead + Unpin + Send
+    {
+        let bytes = read_one(io, MAX_RESPONSE_SIZE).await.map_err(invalid_data)?;
+        SyncResponse::decode(&bytes).map_err(invalid_data)
+    }
+
+    async fn write_request<T>(&mut self, _: &SyncProtocol, io: &mut T, request: SyncRequest) -> io::Result<()>
+        where T: AsyncWrite + Unpin + Send
+    {
+        write_one(io, request.encode()).await
+    }
+
+    async fn write_response<T>(&mut self, _: &SyncProtocol, io: &mut T, response: SyncResponse) -> io::Result<()>
+        where T: AsyncWrite + Unpin + Send
+    {
+        write_one(io, response.encode()).await
+    }
+}
+
+#[derive(Debug)]
+enum SyncState {
+    Idle,
+    // Waiting on headers from the peer
+    Headers { peer: PeerId },
+    // Downloading the blocks for headers we've checked a batch at a time
+    // (more_headers is set if the peer had more headers than fit in one response)
+    Blocks { peer: PeerId, headers: VecDeque<BlockHeader>, requested: u64, more_headers: bool },
+}
+
+impl Default for SyncState {
+    fn default() -> Self {
+        SyncState::Idle
+    }
+}
+
+// Catches us up to peers whose chains have more work than ours, one peer at a time
+// Headers come first so we know exactly which blocks we're missing, then the blocks in batches
+// Every method that moves the sync along returns the next request to send (and who to send it to)
+#[derive(Debug, Default)]
+pub struct ChainSync {
+    state: SyncState,
+    // Peers that told us about a chain with more work than ours, with how much work they claimed
+    candidates: HashMap<PeerId, u128>,
+    // Blocks gossiped while we were syncing (with who sent them), handled once we're caught up
+    deferred: Vec<(PeerId, Block)>,
+    // Gossiped blocks waiting on a parent we don't have, keyed by the parent's hash
+    orphans: HashMap<BlockHash, Vec<Block>>,
+    // Parents we've asked for by hash and who we asked
+    requested_parents: HashMap<BlockHash, PeerId>,
+}
+
+impl ChainSync {
+    pub fn is_syncing(&self) -> bool {
+        !matches!(self.state, SyncState::Idle)
+    }
+
+    // A peer told us where its chain ends, sync from it if it's ahead of us
+    pub fn tip_received(&mut self, peer: PeerId, tip: ChainTip, blockchain: &Blockchain) -> Option<(PeerId, SyncRequest)> {
+        if tip.total_work > blockchain.total_work() && !blockchain.has_block(&tip.hash) {
+            self.candidates.insert(peer, tip.total_work);
+        } else {
+            self.candidates.remove(&peer);
+        }
+
+        self.start(blockchain)
+    }
+
+    // Ask the candidate with the most work for headers, unless we're already syncing
+    fn start(&mut self, blockchain: &Blockchain) -> Option<(PeerId, SyncRequest)> {
+        if self.is_syncing() {
+            return None;
+        }
+        // Blocks we got since they told us their tip may have caught us up
+        let our_work = blockchain.total_work();
+        self.candidates.retain(|_, work| *work > our_work);
+        let peer = self.candidates.iter().max_by_key(|(_, work)| **work).map(|(peer, _)| peer.clone())?;
+
+        self.state = SyncState::Headers { peer: peer.clone() };
+        Some((peer, SyncRequest::GetHeaders { locator: blockchain.locator(), max: MAX_HEADERS }))
+    }
+
+    // Give up on a peer (it sent something bad or stopped answering) and move on to the next one
+    fn abort(&mut self, peer: &PeerId, blockchain: &Blockchain) -> Option<(PeerId, SyncRequest)> {
+        self.candidates.remove(peer);
+        self.state = SyncState::Idle;
+
+        self.start(blockchain)
+    }
+
+    fn syncing_from(&self) -> Option<&PeerId> {
+        match &self.state {
+            SyncState::Idle => None,
+            SyncState::Headers { peer } | SyncState::Blocks { peer, .. } => Some(peer),
+        }
+    }
+
+    // Check the headers follow on from a block we have and from each other, then start fetching their blocks
+    pub fn headers_received(&mut self, peer: PeerId, headers: Vec<BlockHeader>, blockchain: &Blockchain) -> Option<(PeerId, SyncRequest)> {
+        match &self.state {
+            SyncState::Headers { peer: expected } if *expected == peer => {},
+            _ => return None,
+        }
+        let more_headers = headers.len() as u64 >= MAX_HEADERS;
+        // The peer starts from where it thinks our chains split, skip anything we already have
+        let headers: VecDeque<BlockHeader> = headers.into_iter()
+            .skip_while(|header| blockchain.has_block(&header.hash))
+            .collect();
+        if let Err(reason) = Self::check_headers(&headers, blockchain) {
+            eprintln!("Bad headers from {}: {}", peer, reason);
+            return self.abort(&peer, blockchain);
+        }
+        // Nothing new, their chain wasn't ahead of ours after all
+        if headers.is_empty() {
+            return self.abort(&peer, blockchain);
+        }
+
+        self.state = SyncState::Blocks { peer, headers, requested: 0, more_headers };
+        self.request_blocks()
+    }
+
+    // Only the proof of work and how the headers link up can be checked without the blocks, add_block checks the rest
+    fn check_headers(headers: &VecDeque<BlockHeader>, blockchain: &Blockchain) -> Result<(), &'static str> {
+        let first = match headers.front() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let parent = blockchain.get_block(&first.previous_hash).ok_or("headers don't build on a block we have")?;
+        let mut expected_index = parent.index() + 1;
+        let mut previous_hash = parent.hash();
+        for header in headers.iter() {
+            if header.previous_hash != previous_hash || header.index != expected_index {
+                return Err("headers aren't a chain");
+            }
+            if !header.has_valid_hash() || !header.meets_target() {
+                return Err("header hash is invalid or doesn't meet its target");
+            }
+            expected_index += 1;
+            previous_hash = header.hash.clone();
+        }
+
+        Ok(())
+    }
+
+    // Ask for the next batch of blocks we have headers for
+    fn request_blocks(&mut self) -> Option<(PeerId, SyncRequest)> {
+        match &mut self.state {
+            SyncState::Blocks { peer, headers, requested, .. } => {
+                let start = headers.front()?.index;
+                *requested = MAX_BLOCKS.min(headers.len() as u64);
+                Some((peer.clone(), SyncRequest::GetBlocksByRange { start, count: *requested }))
+            },
+            _ => None,
+        }
+    }
+
+    // Blocks we asked for arrived, hands them back in order if they're the ones the headers promised (None if we weren't expecting any)
+    // Once they've been added call blocks_added to carry on
+    pub fn blocks_received(&mut self, peer: PeerId, blocks: Vec<Block>) -> Option<Vec<Block>> {
+        let matches_headers = match &mut self.state {
+            SyncState::Blocks { peer: expected, headers, requested, .. } if *expected == peer => {
+                let matches_headers = blocks.len() as u64 == *requested
+                    && blocks.iter().zip(headers.iter()).all(|(block, header)| block.hash() == header.hash);
+                if matches_headers {
+                    headers.drain(..blocks.len());
+                }
+                matches_headers
+            },
+            _ => return None,
+        };
+        if !matches_headers {
+            eprintln!("Blocks from {} don't match their headers", peer);
+            self.candidates.remove(&peer);
+            self.state = SyncState::Idle;
+            return Some(Vec::new());
+        }
+
+        Some(blocks)
+    }
+
+    // Move on after adding a batch of blocks (all_added is false if any of them were rejected)
+    pub fn blocks_added(&mut self, all_added: bool, blockchain: &Blockchain) -> Option<(PeerId, SyncRequest)> {
+        let (peer, headers_left, more_headers) = match &self.state {
+            SyncState::Blocks { peer, headers, more_headers, .. } => (peer.clone(), !headers.is_empty(), *more_headers),
+            _ => return self.start(blockchain),
+        };
+        if !all_added {
+            return self.abort(&peer, blockchain);
+        }
+        if headers_left {
+            return self.request_blocks();
+        }
+        // Our tip is now the last block they sent, so a fresh locator picks up right after it
+        if more_headers {
+            self.state = SyncState::Headers { peer: peer.clone() };
+            return Some((peer, SyncRequest::GetHeaders { locator: blockchain.locator(), max: MAX_HEADERS }));
+        }
+
+        // Caught up with this peer, see if anyone else is further ahead
+        self.abort(&peer, blockchain)
+    }
+
+    // A request to a peer failed or timed out
+    pub fn request_failed(&mut self, peer: &PeerId, blockchain: &Blockchain) -> Option<(PeerId, SyncRequest)> {
+        // Parents we were waiting on from it aren't coming
+        let failed: Vec<BlockHash> = self.requested_parents.iter()
+            .filter(|(_, requested_from)| *requested_from == peer)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in failed {
+            self.parent_not_found(&hash);
+        }
+        if self.syncing_from() == Some(peer) {
+            return self.abort(peer, blockchain);
+        }
+        self.candidates.remove(peer);
+
+        None
+    }
+
+    // A gossiped block builds on one we don't have, hold on to it and ask the peer that sent it for the parent
+    // If too many are waiting we're further behind than a few blocks, so ask for the peer's tip to sync from instead
+    pub fn missing_parent(&mut self, peer: PeerId, block: Block) -> Option<(PeerId, SyncRequest)> {
+        if self.orphans.values().map(Vec::len).sum::<usize>() >= MAX_ORPHANS {
+            return Some((peer, SyncRequest::GetTip));
+        }
+        let parent_hash = *block.previous_hash();
+        self.orphans.entry(parent_hash).or_insert_with(Vec::new).push(block);
+        if self.requested_parents.contains_key(&parent_hash) {
+            return None;
+        }
+
+        self.requested_parents.insert(parent_hash, peer.clone());
+        Some((peer, SyncRequest::GetBlockByHash(parent_hash)))
+    }
+
+    // A block we asked for by hash arrived, hands it back if we were waiting on it
+    pub fn parent_received(&mut self, block: Block) -> Option<Block> {
+        self.requested_parents.remove(&*block.hash()).map(|_| block)
+    }
+
+    // The peer didn't have a parent we asked for, so the blocks waiting on it can't be added
+    pub fn parent_not_found(&mut self, hash: &BlockHash) {
+        self.requested_parents.remove(hash);
+        self.orphans.remove(hash);
+    }
+
+    // Blocks that were waiting on this one, to add now that we have it
+    pub fn take_orphans(&mut self, hash: &BlockHash) -> Vec<Block> {
+        self.orphans.remove(hash).unwrap_or_default()
+    }
+
+    // Hold on to a gossiped block until we're done syncing
+    pub fn defer_block(&mut self, peer: PeerId, block: Block) {
+        if self.deferred.len() < MAX_DEFERRED_BLOCKS {
+            self.deferred.push((peer, block));
+        }
+    }
+
+    // Gossiped blocks to handle now that we're caught up
+    pub fn take_deferred(&mut self) -> Vec<(PeerId, Block)> {
+        if self.is_syncing() {
+            return Vec::new();
+        }
+
+        self.deferred.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Genesis, Target};
+
+    fn empty_chain() -> Blockchain {
+        Blockchain::new(Genesis::default(), Target::from_compact(0x200fffff).unwrap())
+    }
+
+    // A chain of some empty blocks on an easy target
+    fn chain(blocks: u64) -> Blockchain {
+        let mut blockchain = empty_chain();
+        let miner = PeerId::random();
+        for _ in 0..blocks {
+            let mut block = blockchain.next_block(&miner, Vec::new()).unwrap();
+            let mut nonce = 0;
+            while !block.meets_target() {
+                nonce += 1;
+                block.set_nonce(nonce);
+            }
+            blockchain.add_block(block).unwrap();
+        }
+
+        blockchain
+    }
+
+    // A chain with the first few blocks of another one
+    fn behind(ahead: &Blockchain, blocks: u64) -> Blockchain {
+        let mut blockchain = empty_chain();
+        for height in 1..=blocks {
+            blockchain.add_block(ahead.block_at_height(height).unwrap()).unwrap();
+        }
+
+        blockchain
+    }
+
+    // What the peer with the chain would send back
+    fn answer(blockchain: &Blockchain, request: SyncRequest) -> SyncResponse {
+        match request {
+            SyncRequest::GetTip => SyncResponse::Tip(ChainTip::of(blockchain)),
+            SyncRequest::GetHeaders { locator, max } => SyncResponse::Headers(blockchain.headers_after(&locator, max)),
+            SyncRequest::GetBlocksByRange { start, count } => {
+                SyncResponse::Blocks((start..start + count).filter_map(|height| blockchain.block_at_height(height)).collect())
+            },
+            SyncRequest::GetBlockByHash(hash) => blockchain.get_block(&hash).map_or(SyncResponse::NotFound(hash), SyncResponse::Block),
+        }
+    }
+
+    #[test]
+    fn syncs_headers_then_blocks_in_batches() {
+        let theirs = chain(MAX_BLOCKS + 4);
+        let mut ours = behind(&theirs, 2);
+        let peer = PeerId::random();
+        let mut sync = ChainSync::default();
+
+        // Idle until a peer shows us a chain with more work, then headers first
+        assert!(!sync.is_syncing());
+        let (to, request) = sync.tip_received(peer.clone(), ChainTip::of(&theirs), &ours).unwrap();
+        assert_eq!(to, peer);
+        assert!(matches!(request, SyncRequest::GetHeaders { .. }));
+        assert!(sync.is_syncing());
+        let headers = match answer(&theirs, request) {
+            SyncResponse::Headers(headers) => headers,
+            response => panic!("expected headers, got {:?}", response),
+        };
+        // Only the peer we're syncing from gets to answer
+        assert_eq!(sync.headers_received(PeerId::random(), headers.clone(), &ours), None);
+
+        // Then the blocks after the ones we have, a batch at a time
+        let mut request = sync.headers_received(peer.clone(), headers, &ours).map(|(_, request)| request);
+        assert_eq!(request, Some(SyncRequest::GetBlocksByRange { start: 3, count: MAX_BLOCKS }));
+        while let Some(next) = request {
+            let blocks = match answer(&theirs, next) {
+                SyncResponse::Blocks(blocks) => sync.blocks_received(peer.clone(), blocks).unwrap(),
+                response => panic!("expected blocks, got {:?}", response),
+            };
+            for block in blocks {
+                ours.add_block(block).unwrap();
+            }
+            request = sync.blocks_added(true, &ours).map(|(_, request)| request);
+        }
+
+        // Caught up with nobody else ahead, so back to idle
+        assert!(!sync.is_syncing());
+        assert_eq!(ours.latest_block(), theirs.latest_block());
+        assert_eq!(sync.tip_received(peer, ChainTip::of(&theirs), &ours), None);
+    }
+
+    #[test]
+    fn blocks_that_dont_match_the_headers_end_the_sync() {
+        let theirs = chain(5);
+        let ours = behind(&theirs, 1);
+        let peer = PeerId::random();
+        let mut sync = ChainSync::default();
+        let (_, request) = sync.tip_received(peer.clone(), ChainTip::of(&theirs), &ours).unwrap();
+        let headers = match answer(&theirs, request) {
+            SyncResponse::Headers(headers) => headers,
+            response => panic!("expected headers, got {:?}", response),
+        };
+        sync.headers_received(peer.clone(), headers, &ours).unwrap();
+
+        // One short, and from someone else entirely
+        let blocks: Vec<Block> = (2..5).filter_map(|height| theirs.block_at_height(height)).collect();
+        assert_eq!(sync.blocks_received(PeerId::random(), blocks.clone()), None);
+        assert_eq!(sync.blocks_received(peer.clone(), blocks), Some(Vec::new()));
+        assert!(!sync.is_syncing());
+        // The peer isn't a candidate anymore
+        assert_eq!(sync.blocks_added(true, &ours), None);
+    }
+
+    #[test]
+    fn orphans_wait_for_their_parent() {
+        let theirs = chain(3);
+        let ours = behind(&theirs, 1);
+        let peer = PeerId::random();
+        let mut sync = ChainSync::default();
+        let parent = theirs.block_at_height(2).unwrap();
+        let orphan = theirs.block_at_height(3).unwrap();
+
+        // The parent is asked for once however many blocks are waiting on it
+        assert_eq!(sync.missing_parent(peer.clone(), orphan.clone()), Some((peer.clone(), SyncRequest::GetBlockByHash(*parent.hash()))));
+        assert_eq!(sync.missing_parent(PeerId::random(), orphan.clone()), None);
+
+        // Blocks we didn't ask for aren't handed back
+        assert_eq!(sync.parent_received(theirs.block_at_height(1).unwrap()), None);
+        assert_eq!(sync.parent_received(parent.clone()), Some(parent.clone()));
+        assert_eq!(sync.take_orphans(&parent.hash()), vec![orphan.clone(), orphan.clone()]);
+        assert!(sync.take_orphans(&parent.hash()).is_empty());
+
+        // Parents that never come take their orphans with them, whether the peer says so or stops answering
+        sync.missing_parent(peer.clone(), orphan.clone());
+        sync.parent_not_found(&parent.hash());
+        assert!(sync.take_orphans(&parent.hash()).is_empty());
+        sync.missing_parent(peer.clone(), orphan.clone());
+        assert_eq!(sync.request_failed(&peer, &ours), None);
+        assert!(sync.take_orphans(&parent.hash()).is_empty());
+    }
+
+    #[test]
+    fn too_many_orphans_asks_for_the_tip() {
+        let theirs = chain(3);
+        let peer = PeerId::random();
+        let mut sync = ChainSync::default();
+        let orphan = theirs.block_at_height(3).unwrap();
+        for _ in 0..MAX_ORPHANS {
+            sync.missing_parent(peer.clone(), orphan.clone());
+        }
+
+        // We're too far behind to fetch parents one at a time
+        assert_eq!(sync.missing_parent(peer.clone(), orphan), Some((peer, SyncRequest::GetTip)));
+    }
+
+    #[test]
+    fn gossiped_blocks_are_deferred_until_synced() {
+        let theirs = chain(3);
+        let ours = behind(&theirs, 1);
+        let peer = PeerId::random();
+        let mut sync = ChainSync::default();
+        sync.tip_received(peer.clone(), ChainTip::of(&theirs), &ours).unwrap();
+
+        let block = theirs.block_at_height(3).unwrap();
+        sync.defer_block(peer.clone(), block.clone());
+        assert!(sync.take_deferred().is_empty());
+
+        // The peer stopped answering, so there's nothing left to sync from
+        assert_eq!(sync.request_failed(&peer, &ours), None);
+        assert!(!sync.is_syncing());
+        assert_eq!(sync.take_deferred(), vec![(peer, block)]);
+        assert!(sync.take_deferred().is_empty());
+    }
+}